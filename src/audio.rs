@@ -1,9 +1,10 @@
+use crate::model::Model;
 use crate::{DOTS_HZ, GB};
+use log::{debug, warn};
 use raylib::prelude::*;
 use std::error::Error;
 
 const AUDIO_FREQ: u16 = 48_000;
-const VOLUME_DIAL: f32 = 1.0;
 const AUDIO_BUFFER_SIZE: usize = 0x1000;
 
 #[derive(Default)]
@@ -12,7 +13,8 @@ impl HiresTimer {
 	/// Run this function each time an event happens,
 	///     to measure the frequency of the event.
 	fn tick(&mut self, dots: u64) {
-		println!(
+		debug!(
+			target: "apu",
 			"HTimer: {} hz ({} dots)",
 			DOTS_HZ as u64 / (dots - self.0),
 			dots - self.0,
@@ -119,7 +121,17 @@ pub struct AudioParams {
 	wave_ram: [u8; 16],
 }
 impl AudioParams {
-	pub fn set(&mut self, addr: usize, data: u8) {
+	pub fn set(&mut self, addr: usize, data: u8, model: Model) {
+		// While the APU is off, NR10-NR51 ignore writes, except for the
+		// length timers in NRx1 on pre-CGB models
+		if self.nr52 & 0x80 == 0 && (0xFF10..=0xFF25).contains(&addr) {
+			if !model.is_cgb() && matches!(addr, 0xFF11 | 0xFF16 | 0xFF1B | 0xFF20) {
+				let channel = &mut self.channels[(addr - 0xFF10) / 5];
+				let mask = if addr == 0xFF1B { 0xFF } else { 0b_0011_1111 };
+				channel.nr[1] = (channel.nr[1] & !mask) | (data & mask);
+			}
+			return;
+		}
 		match addr {
 			0xFF10 => self.channels[0].nr[0] = data,
 			0xFF11 => self.channels[0].nr[1] = data,
@@ -163,10 +175,31 @@ impl AudioParams {
 			}
 			0xFF24 => self.nr50 = data,
 			0xFF25 => self.nr51 = data,
-			0xFF26 => self.nr52 = data,
+			0xFF26 => {
+				if self.nr52 & 0x80 != 0 && data & 0x80 == 0 {
+					self.power_off(model);
+				}
+				self.nr52 = data;
+			}
 			0xFF30..=0xFF3F => self.wave_ram[addr - 0xFF30] = data,
-			_ => println!("invalid audio write! {addr:#x}"),
+			_ => warn!(target: "apu", "invalid audio write! {addr:#x}"),
+		}
+	}
+	// Turning the APU off clears its registers, but not wave RAM. The length
+	// timers in NRx1 survive on pre-CGB models.
+	fn power_off(&mut self, model: Model) {
+		for (i, channel) in self.channels.iter_mut().enumerate() {
+			// the wave channel's length is the whole of NR31
+			let mask = if i == 2 { 0xFF } else { 0b_0011_1111 };
+			let length = match model.is_cgb() {
+				true => 0,
+				false => channel.nr[1] & mask,
+			};
+			*channel = Channel::default();
+			channel.nr[1] = length;
 		}
+		self.nr50 = 0;
+		self.nr51 = 0;
 	}
 }
 
@@ -218,10 +251,10 @@ pub struct APU<'a> {
 	debug_timer: HiresTimer,
 }
 impl<'a> APU<'a> {
-	pub fn new(device: &'a RaylibAudio) -> Self {
+	pub fn new(device: &'a RaylibAudio, volume: f32) -> Self {
 		let stream = device.new_audio_stream(AUDIO_FREQ as u32, 16, 1);
 
-		stream.set_volume(VOLUME_DIAL);
+		stream.set_volume(volume);
 		stream.play();
 
 		Self {
@@ -0,0 +1,145 @@
+use crate::GB;
+use crate::compat;
+use crate::cpu::Flags;
+use crate::ioreg::DivRegister;
+use crate::model::Model;
+use std::error::Error;
+
+// The boot ROM draws this next to the logo from the cartridge header
+const REGISTERED_MARK: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+pub fn load_boot_rom(gb: &mut GB, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+	let model = gb.bus.io.model;
+	if data.len() != model.boot_rom_size() {
+		return Err(format!(
+			"{model:?} boot ROM must be {} bytes, got {}",
+			model.boot_rom_size(),
+			data.len()
+		)
+		.into());
+	}
+	gb.bus.boot_rom = data;
+	Ok(())
+}
+
+// Double each bit of a nibble: 0b1010 -> 0b11001100
+fn double_bits(nibble: u8) -> u8 {
+	(0..4).fold(0, |acc, i| acc | (((nibble >> i) & 1) * 0b11) << (i * 2))
+}
+
+fn draw_logo(gb: &mut GB) {
+	// Decompress the header logo into tiles 1-24, one bitplane only
+	let logo: Vec<u8> = gb.bus.cart.rom[0][0x104..0x134].to_vec();
+	let mut ofs = 0x10;
+	for byte in logo {
+		for nibble in [byte >> 4, byte & 0xf] {
+			gb.bus.vram[ofs] = double_bits(nibble);
+			gb.bus.vram[ofs + 2] = double_bits(nibble);
+			ofs += 4;
+		}
+	}
+	for (i, row) in REGISTERED_MARK.iter().enumerate() {
+		gb.bus.vram[0x190 + i * 2] = *row;
+	}
+
+	// Two rows of 12 tiles, with the mark after the first row
+	for i in 0..12 {
+		gb.bus.vram[0x1904 + i] = 1 + i as u8;
+		gb.bus.vram[0x1924 + i] = 13 + i as u8;
+	}
+	gb.bus.vram[0x1910] = 0x19;
+}
+
+/// Put the system in the state the boot ROM leaves it in when it hands over to the cartridge.
+pub fn skip(gb: &mut GB) {
+	let header_checksum = gb.bus.cart.rom[0][0x14D];
+	let model = gb.bus.io.model;
+	let compat = gb.bus.io.dmg_compat;
+
+	let cpu = &mut gb.cpu;
+	cpu.sp = 0xFFFE;
+	cpu.pc = 0x0100;
+	cpu.f = Flags {
+		z: true,
+		n: false,
+		h: false,
+		c: false,
+	};
+	match model {
+		Model::DMG | Model::MGB => {
+			cpu.a = if model == Model::MGB { 0xFF } else { 0x01 };
+			(cpu.b, cpu.c) = (0x00, 0x13);
+			(cpu.d, cpu.e) = (0x00, 0xD8);
+			(cpu.h, cpu.l) = (0x01, 0x4D);
+			cpu.f.h = header_checksum != 0;
+			cpu.f.c = header_checksum != 0;
+		}
+		Model::SGB => {
+			(cpu.a, cpu.b, cpu.c) = (0x01, 0x00, 0x14);
+			(cpu.d, cpu.e) = (0x00, 0x00);
+			(cpu.h, cpu.l) = (0xC0, 0x60);
+			cpu.f.z = false;
+		}
+		Model::CGB | Model::AGB => {
+			cpu.a = 0x11;
+			// the GBA boot ROM tells itself apart with B
+			if model == Model::AGB {
+				(cpu.b, cpu.c) = (0x01, 0x00);
+				cpu.f.z = false;
+			} else {
+				(cpu.b, cpu.c) = (0x00, 0x00);
+			}
+			(cpu.d, cpu.e) = (0x00, 0x08);
+			(cpu.h, cpu.l) = (0x00, 0x7C);
+		}
+	}
+
+	let io = &mut gb.bus.io;
+	io.p1_joyp = 0xCF;
+	io.div = DivRegister::from_counter(match model {
+		Model::DMG | Model::MGB => 0xABCC,
+		Model::SGB => 0xD85C,
+		Model::CGB | Model::AGB => 0x267C,
+	});
+	io.tac = 0x00; // reads as 0xF8
+	io.interrupt = 0x01; // reads as 0xE1
+	io.lcdc = 0x91;
+	io.stat = 0x85;
+	io.bgp = 0xFC;
+	io.hide_boot_rom = true;
+
+	// The boot sound leaves channel 1 configured.
+	// Trigger bits are left out so it doesn't play again. NR52 goes first,
+	// since the APU ignores the others while it is off.
+	for (addr, data) in [
+		(0xFF26, 0xF1),
+		(0xFF10, 0x80),
+		(0xFF11, 0xBF),
+		(0xFF12, 0xF3),
+		(0xFF13, 0xFF),
+		(0xFF14, 0xBF & 0x7F),
+		(0xFF16, 0x3F),
+		(0xFF17, 0x00),
+		(0xFF18, 0xFF),
+		(0xFF19, 0xBF & 0x7F),
+		(0xFF1A, 0x7F),
+		(0xFF1B, 0xFF),
+		(0xFF1C, 0x9F),
+		(0xFF1D, 0xFF),
+		(0xFF1E, 0xBF & 0x7F),
+		(0xFF20, 0xFF),
+		(0xFF21, 0x00),
+		(0xFF22, 0x00),
+		(0xFF23, 0xBF & 0x7F),
+		(0xFF24, 0x77),
+		(0xFF25, 0xF3),
+	] {
+		io.audio_params.set(addr, data, model);
+	}
+
+	if compat {
+		compat::colorize(&mut gb.bus);
+	} else {
+		draw_logo(gb);
+	}
+}
@@ -1,35 +1,18 @@
-use crate::cart::Cartridge;
+use crate::cart::{Cartridge, ROM_BANK_SIZE};
+use crate::cdl::{self, Cdl};
+use crate::debugger::{WatchHit, Watchpoint};
+use crate::iolog::{Event, IoLog};
 use crate::ioreg::IoReg;
+use crate::symbols::Symbols;
+use crate::video;
+use std::cell::RefCell;
 
 pub const VRAM_SIZE: usize = 0x2000;
 pub const WRAM_SIZE: usize = 0x2000;
 
-const BOOT_ROM: [u8; 0x100] = [
-	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-	////////////////////////////////////////////////////////////////
-
-	// set up stack
-	0x31, 0xfe, 0xff, // LD SP, $FFFE
-	//
-
-	// turn on LCD
-	0x3e, 0x91, // ld a, $91
-	0xe0, 0x40, // ldh ($ff40), a
-	//
-
-	// unmap boot rom
-	0x3e, 0x01, // ld a, $01
-	0xe0, 0x50, // ldh ($ff50), a
-];
-
 pub struct Bus {
+	pub boot_rom: Vec<u8>,
+
 	pub vram: [u8; VRAM_SIZE],
 
 	pub wram: [u8; WRAM_SIZE],
@@ -39,25 +22,141 @@ pub struct Bus {
 	pub hram: [u8; 0x7F],
 
 	pub cart: Cartridge,
+
+	// not memory mapped
+	pub framebuffer: [u8; 160 * 144 * 3],
+	pub sprites: Vec<video::Sprite>, // objects on the current scanline
+	pub frame_done: bool,            // set when entering vblank
+	pub frames: u64,
+	pub mcycles: u64,
+	pub watchpoints: Vec<Watchpoint>,
+	pub watch_hit: Option<WatchHit>,
+	pub symbols: Symbols,
+	pub cdl: Option<Cdl>,
+	pub io_log: Option<RefCell<IoLog>>, // peek takes &self, so the log is borrowed when it records
 }
 impl std::default::Default for Bus {
 	fn default() -> Bus {
 		Bus {
+			boot_rom: vec![],
 			vram: [0; VRAM_SIZE],
 			wram: [0; WRAM_SIZE],
 			hram: [0; 0x7f],
 			oam: [0; 0xA0],
 			io: IoReg::default(),
 			cart: Cartridge::default(),
+			framebuffer: [30; 160 * 144 * 3],
+			sprites: vec![],
+			frame_done: false,
+			frames: 0,
+			mcycles: 0,
+			watchpoints: vec![],
+			watch_hit: None,
+			symbols: Symbols::default(),
+			cdl: None,
+			io_log: None,
 		}
 	}
 }
 impl Bus {
-	pub fn peek(&self, addr16: u16) -> u8 {
+	/// Advance everything except the CPU by one M-cycle
+	pub fn tick(&mut self) {
+		self.mcycles += 1;
+		self.io.advance_counter_div(1);
+		self.io.update_joypad();
+		for _ in 0..4 {
+			video::tick_dot(self);
+		}
+	}
+	/// Advance an M-cycle in STOP, where the LCD and timer are halted and
+	/// only the buttons are watched
+	pub fn tick_stopped(&mut self) {
+		self.mcycles += 1;
+		self.io.update_joypad();
+	}
+	/// Bank mapped at an address, numbered the way RGBDS does
+	pub fn bank_at(&self, addr: u16) -> usize {
+		match addr {
+			0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cart.bank_at(addr),
+			0xD000..=0xDFFF => 1,
+			_ => 0,
+		}
+	}
+	/// Offset into the cartridge ROM of an address, if ROM is mapped there
+	pub fn rom_offset(&self, addr: u16) -> Option<usize> {
+		match addr {
+			0x0000..=0x00FF if !self.io.hide_boot_rom => None,
+			0x0200..=0x08FF if !self.io.hide_boot_rom && self.boot_rom.len() > 0x100 => None,
+			0x0000..=0x7FFF => {
+				Some(self.cart.bank_at(addr) * ROM_BANK_SIZE + (addr as usize & 0x3FFF))
+			}
+			_ => None,
+		}
+	}
+	// CPU accesses, each taking one M-cycle
+	pub fn read(&mut self, addr: u16) -> u8 {
+		self.read_as(addr, cdl::DATA)
+	}
+	/// Read, telling the code/data log what the byte is used as
+	pub fn read_as(&mut self, addr: u16, usage: u8) -> u8 {
+		self.tick();
+		let data = self.peek(addr);
+		self.log_usage(addr, usage);
+		self.watch(addr, data, false);
+		data
+	}
+	pub fn write(&mut self, addr: u16, data: u8) {
+		self.tick();
+		self.watch(addr, data, true);
+		self.poke(addr, data);
+	}
+	fn log_access(&self, addr: u16, data: u8, write: bool) {
+		if let Some(log) = &self.io_log
+			&& log.borrow().filter.matches(addr, write, self.frames)
+		{
+			log.borrow_mut().record(Event {
+				frame: self.frames,
+				ly: self.io.ly,
+				dot: self.io.lx,
+				addr,
+				data,
+				write,
+			});
+		}
+	}
+	fn log_usage(&mut self, addr: u16, usage: u8) {
+		if self.cdl.is_some()
+			&& let Some(ofs) = self.rom_offset(addr)
+			&& let Some(cdl) = &mut self.cdl
+		{
+			cdl.mark(ofs, usage);
+		}
+	}
+	fn watch(&mut self, addr: u16, data: u8, write: bool) {
+		if let Some(w) = self.watchpoints.iter().find(|w| w.matches(addr, write)) {
+			self.watch_hit = Some(WatchHit {
+				addr,
+				data,
+				write,
+				kind: w.kind,
+			});
+		}
+	}
+	/// Read without side effects, as an access the IO log sees
+	pub fn peek(&self, addr: u16) -> u8 {
+		let data = self.peek_unlogged(addr);
+		self.log_access(addr, data, false);
+		data
+	}
+	/// Read for display, like disassembly or traces, that the IO log doesn't see
+	pub fn peek_unlogged(&self, addr16: u16) -> u8 {
 		let addr = addr16 as usize;
 		match addr16 {
 			// Boot rom
-			0x0000..=0x00FF if !self.io.hide_boot_rom => BOOT_ROM[addr],
+			0x0000..=0x00FF if !self.io.hide_boot_rom => self.boot_rom[addr],
+			0x0200..=0x08FF if !self.io.hide_boot_rom && self.boot_rom.len() > 0x100 => {
+				self.boot_rom[addr]
+			}
 			// Cartridge (ROM/EXRAM)
 			0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cart.peek(addr16),
 			// vram
@@ -65,7 +164,7 @@ impl Bus {
 			// WRAM
 			0xC000..=0xDFFF => self.wram[addr - 0xC000],
 			// Echo RAM
-			0xE000..=0xFDFF => self.peek(addr16 - 0x2000),
+			0xE000..=0xFDFF => self.peek_unlogged(addr16 - 0x2000),
 			// OAM
 			0xFE00..=0xFE9F => self.oam[addr - 0xFE00],
 			// Not Usable
@@ -79,11 +178,11 @@ impl Bus {
 		}
 	}
 	pub fn poke(&mut self, addr16: u16, data: u8) {
+		self.log_access(addr16, data, true);
 		let addr = addr16 as usize;
 		match addr16 {
-			// Boot rom
-			0x0000..=0x00FF if !self.io.hide_boot_rom => panic!("wrote to boot rom"),
-			// Cartridge (ROM/EXRAM)
+			// Cartridge (ROM/EXRAM). The boot rom only covers reads, so writes
+			// under it still reach the MBC.
 			0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cart.poke(addr16, data),
 			// vram
 			0x8000..=0x9FFF => self.vram[addr - 0x8000] = data,
@@ -99,7 +198,10 @@ impl Bus {
 			0xFF46 => {
 				let ofs = (data as u16) << 8;
 				let x = (ofs..(ofs + 0xA0))
-					.map(|i| self.peek(i))
+					.map(|i| {
+						self.log_usage(i, cdl::DMA);
+						self.peek(i)
+					})
 					.collect::<Vec<u8>>();
 				self.oam.copy_from_slice(&x);
 			}
@@ -0,0 +1,118 @@
+use crate::bus::Bus;
+use crate::disasm;
+use crate::symbols::Symbols;
+use std::collections::VecDeque;
+
+// Shadow call stack and recent instruction history, for reporting what the
+// guest program was doing when something goes wrong.
+
+const MAX_FRAMES: usize = 256; // programs that never return don't grow this forever
+const HISTORY_LEN: usize = 32;
+
+pub struct Frame {
+	pub call_site: (usize, u16), // bank and address of the CALL/RST, or the interrupted PC
+	pub target: (usize, u16),
+	pub sp: u16,         // SP after the return address was pushed
+	pub interrupt: bool, // entered by interrupt dispatch
+}
+
+#[derive(Clone, Copy)]
+struct Executed {
+	bank: usize,
+	pc: u16,
+	bytes: [u8; 3],
+}
+
+#[derive(Default)]
+pub struct CallStack {
+	pub frames: Vec<Frame>,
+	history: VecDeque<Executed>,
+}
+impl CallStack {
+	/// Remember an instruction about to run
+	pub fn record(&mut self, bus: &Bus, pc: u16) {
+		if self.history.len() == HISTORY_LEN {
+			self.history.pop_front();
+		}
+		self.history.push_back(Executed {
+			bank: bus.bank_at(pc),
+			pc,
+			bytes: [0, 1, 2].map(|i| bus.peek_unlogged(pc.wrapping_add(i))),
+		});
+	}
+
+	pub fn call(&mut self, frame: Frame) {
+		if self.frames.len() == MAX_FRAMES {
+			self.frames.remove(0);
+		}
+		self.frames.push(frame);
+	}
+
+	/// Drop the frames whose return address is no longer on the stack
+	pub fn ret(&mut self, sp: u16) {
+		while self.frames.last().is_some_and(|frame| frame.sp < sp) {
+			self.frames.pop();
+		}
+	}
+
+	/// Innermost frame first, starting from the current PC
+	pub fn backtrace(&self, bus: &Bus, pc: u16) -> Vec<String> {
+		let mut out = vec![format!(
+			"#0  {}",
+			location(&bus.symbols, (bus.bank_at(pc), pc))
+		)];
+		for (i, frame) in self.frames.iter().rev().enumerate() {
+			let kind = if frame.interrupt {
+				format!("interrupt ${:04x}", frame.target.1)
+			} else {
+				format!("call to {}", location(&bus.symbols, frame.target))
+			};
+			out.push(format!(
+				"#{:<2} {} ({kind})",
+				i + 1,
+				location(&bus.symbols, frame.call_site)
+			));
+		}
+		out
+	}
+
+	/// Recently executed instructions, oldest first
+	pub fn history(&self, bus: &Bus) -> Vec<String> {
+		self.history
+			.iter()
+			.map(|executed| {
+				let fetch = |a: u16| executed.bytes[a.wrapping_sub(executed.pc) as usize % 3];
+				let mut instr = disasm::decode(executed.pc, fetch);
+				if let Some(target) = instr.target {
+					instr.label_target(&bus.symbols, bus.bank_at(target));
+				}
+				disasm::format_line(executed.bank, executed.pc, &instr, fetch)
+			})
+			.collect()
+	}
+
+	/// Everything known about where the guest was, for crash reports
+	pub fn report(&self, bus: &Bus, pc: u16) -> String {
+		let mut out = vec!["Guest backtrace:".to_string()];
+		out.extend(
+			self.backtrace(bus, pc)
+				.into_iter()
+				.map(|line| format!("  {line}")),
+		);
+		out.push(format!("Last {} instructions:", self.history.len()));
+		out.extend(
+			self.history(bus)
+				.into_iter()
+				.map(|line| format!("  {line}")),
+		);
+		out.join("\n")
+	}
+}
+
+/// Bank, address and label, if any
+pub fn location(symbols: &Symbols, (bank, addr): (usize, u16)) -> String {
+	match symbols.describe(bank, addr) {
+		Some(label) => format!("{bank:02x}:{addr:04x} {label}"),
+		None => format!("{bank:02x}:{addr:04x}"),
+	}
+}
@@ -1,3 +1,4 @@
+use log::{debug, info, warn};
 use std::error::Error;
 
 pub const ROM_BANK_SIZE: usize = 0x4000;
@@ -7,6 +8,7 @@ pub const EXRAM_BANK_SIZE: usize = 0x2000;
 pub enum MBCType {
 	MBC0,
 	MBC1,
+	MBC5,
 }
 impl MBCType {
 	pub fn from_header(n: u8) -> MBCType {
@@ -14,11 +16,52 @@ impl MBCType {
 			0 => MBCType::MBC0,
 			1 => MBCType::MBC1,
 			3 => MBCType::MBC1,
+			0x19..=0x1E => MBCType::MBC5,
 			x => panic!("Unknown MBC: {x:#x}"),
 		}
 	}
 }
 
+/// Name of a cartridge type from the header at 0x147
+pub fn type_name(code: u8) -> &'static str {
+	match code {
+		0x00 => "ROM ONLY",
+		0x01 => "MBC1",
+		0x02 => "MBC1+RAM",
+		0x03 => "MBC1+RAM+BATTERY",
+		0x05 => "MBC2",
+		0x06 => "MBC2+BATTERY",
+		0x08 => "ROM+RAM",
+		0x09 => "ROM+RAM+BATTERY",
+		0x0B => "MMM01",
+		0x0C => "MMM01+RAM",
+		0x0D => "MMM01+RAM+BATTERY",
+		0x0F => "MBC3+TIMER+BATTERY",
+		0x10 => "MBC3+TIMER+RAM+BATTERY",
+		0x11 => "MBC3",
+		0x12 => "MBC3+RAM",
+		0x13 => "MBC3+RAM+BATTERY",
+		0x19 => "MBC5",
+		0x1A => "MBC5+RAM",
+		0x1B => "MBC5+RAM+BATTERY",
+		0x1C => "MBC5+RUMBLE",
+		0x1D => "MBC5+RUMBLE+RAM",
+		0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+		0x20 => "MBC6",
+		0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+		0xFC => "POCKET CAMERA",
+		0xFD => "BANDAI TAMA5",
+		0xFE => "HuC3",
+		0xFF => "HuC1+RAM+BATTERY",
+		_ => "unknown",
+	}
+}
+
+// Every header type with a battery, whatever the MBC
+const BATTERY_TYPES: [u8; 13] = [
+	0x03, 0x06, 0x09, 0x0D, 0x0F, 0x10, 0x13, 0x1B, 0x1E, 0x22, 0xFC, 0xFE, 0xFF,
+];
+
 pub struct Cartridge {
 	pub rom: Vec<[u8; ROM_BANK_SIZE]>,
 	pub rom_bank: usize,
@@ -26,12 +69,15 @@ pub struct Cartridge {
 	pub exram: Vec<[u8; EXRAM_BANK_SIZE]>,
 	pub exram_bank: usize,
 	pub exram_enable: bool,
+	pub battery: bool, // external RAM is kept between sessions
+
+	pub has_rumble: bool,
+	pub rumble: bool,      // motor on
+	pub rumble_seen: bool, // motor turned on since the frontend last looked
 
 	pub bank_mode: bool,
 
 	pub mbc: MBCType,
-
-	pub debug_bank_switch: bool,
 }
 impl std::default::Default for Cartridge {
 	fn default() -> Self {
@@ -42,12 +88,15 @@ impl std::default::Default for Cartridge {
 			exram: vec![],
 			exram_bank: 0,
 			exram_enable: false,
+			battery: false,
+
+			has_rumble: false,
+			rumble: false,
+			rumble_seen: false,
 
 			bank_mode: false,
 
 			mbc: MBCType::MBC0,
-
-			debug_bank_switch: false,
 		}
 	}
 }
@@ -56,10 +105,12 @@ impl Cartridge {
 		self.rom = vec![];
 
 		self.mbc = MBCType::from_header(rom[0x147]);
+		self.battery = BATTERY_TYPES.contains(&rom[0x147]);
+		self.has_rumble = matches!(rom[0x147], 0x1C..=0x1E);
 
 		assert!(rom[0x148] < 9);
 		if rom[0x148] > 4 {
-			println!(">=1MiB cart! ram/rom addressing might be weird.");
+			warn!(target: "cart", ">=1MiB cart! ram/rom addressing might be weird.");
 		}
 		let cart_rom_banks = 2 << rom[0x148];
 
@@ -72,7 +123,8 @@ impl Cartridge {
 			_ => panic!(),
 		};
 
-		println!(
+		info!(
+			target: "cart",
 			"{:?}, ROM BANKS:{}, RAM BANKS:{}, cart size:{}",
 			self.mbc,
 			cart_rom_banks,
@@ -101,37 +153,66 @@ impl Cartridge {
 					self.exram.push([0; EXRAM_BANK_SIZE]);
 				}
 			}
+			MBCType::MBC5 => {
+				assert!(rom.len() == ROM_BANK_SIZE * cart_rom_banks);
+				while rom.len() > 0 {
+					self.rom.push((&rom[..ROM_BANK_SIZE]).try_into()?);
+					rom = &rom[ROM_BANK_SIZE..];
+				}
+				for _ in 0..cart_ram_banks {
+					self.exram.push([0; EXRAM_BANK_SIZE]);
+				}
+			}
 		}
 		Ok(())
 	}
+	/// External RAM contents, for battery saves
+	pub fn save_ram(&self) -> Vec<u8> {
+		self.exram.concat()
+	}
+	pub fn load_ram(&mut self, data: &[u8]) {
+		for (bank, chunk) in self.exram.iter_mut().zip(data.chunks(EXRAM_BANK_SIZE)) {
+			bank[..chunk.len()].copy_from_slice(chunk);
+		}
+	}
+	/// ROM bank mapped at a CPU address, or the external RAM bank
+	pub fn bank_at(&self, addr16: u16) -> usize {
+		match self.mbc {
+			MBCType::MBC0 => match addr16 {
+				0x0000..=0x3FFF => 0,
+				0x4000..=0x7FFF => 1,
+				_ => 0,
+			},
+			MBCType::MBC1 => {
+				// TODO:  we currently do not support MBC1 multi-carts
+				let bank_advanced_ofs = if self.bank_mode {
+					self.exram_bank << 5
+				} else {
+					0
+				};
+				match addr16 {
+					0x0000..=0x3FFF => bank_advanced_ofs,
+					0x4000..=0x7FFF => bank_advanced_ofs + self.rom_bank.max(1),
+					_ => self.exram_bank,
+				}
+			}
+			// bank 0 can be mapped at 4000 too
+			MBCType::MBC5 => match addr16 {
+				0x0000..=0x3FFF => 0,
+				0x4000..=0x7FFF => self.rom_bank % self.rom.len(),
+				_ => self.exram_bank,
+			},
+		}
+	}
 	pub fn peek(&self, addr16: u16) -> u8 {
 		let addr = addr16 as usize;
 		match addr16 {
 			// ROM
-			0x0000..=0x7FFF => {
-				match self.mbc {
-					MBCType::MBC0 => match addr {
-						0x0000..=0x3FFF => self.rom[0][addr],
-						_ => self.rom[1][addr - 0x4000],
-					},
-					MBCType::MBC1 => {
-						// TODO:  we currently do not support MBC1 multi-carts
-						let bank_advanced_ofs = if self.bank_mode {
-							self.exram_bank << 5
-						} else {
-							0
-						};
-						match addr {
-							0x0000..=0x3FFF => self.rom[bank_advanced_ofs][addr],
-							_ => self.rom[bank_advanced_ofs + self.rom_bank.max(1)][addr - 0x4000],
-						}
-					}
-				}
-			}
+			0x0000..=0x7FFF => self.rom[self.bank_at(addr16)][addr & 0x3FFF],
 			// external ram bank N
 			0xA000..=0xBFFF => match self.mbc {
 				MBCType::MBC0 => 0xFF,
-				MBCType::MBC1 => {
+				MBCType::MBC1 | MBCType::MBC5 => {
 					if self.exram_bank >= self.exram.len() {
 						0xFF
 					} else if !self.exram_enable {
@@ -150,36 +231,52 @@ impl Cartridge {
 			// ROM
 			0x0000..=0x7FFF => {
 				match self.mbc {
-					MBCType::MBC0 => println!("attempted to write to ROM with MBC 0"),
+					MBCType::MBC0 => warn!(target: "cart", "attempted to write to ROM with MBC 0"),
 					MBCType::MBC1 => match addr {
 						0x0000..=0x1FFF => self.exram_enable = data & 0xF == 0xA,
 						0x2000..=0x3FFF => {
-							if self.debug_bank_switch {
-								println!("ROM BANK {} {:02x}", "  ".repeat(data as usize), data);
-							}
+							debug!(target: "cart", "ROM BANK {} {:02x}", "  ".repeat(data as usize), data);
 							self.rom_bank = data as usize & 0x1F
 						}
 						// TODO: could be upper bits of rom bank number on some carts:
 						0x4000..=0x5FFF => {
 							self.exram_bank = data as usize & 3;
-							if self.debug_bank_switch {
-								println!("EXRAM BANK {:02x}", data);
-							}
+							debug!(target: "cart", "EXRAM BANK {:02x}", data);
 						}
 						_ => {
 							self.bank_mode = data & 1 != 0;
-							if self.debug_bank_switch {
-								println!("BANK MODE: advanced={}", self.bank_mode);
-							}
+							debug!(target: "cart", "BANK MODE: advanced={}", self.bank_mode);
+						}
+					},
+					MBCType::MBC5 => match addr {
+						0x0000..=0x1FFF => self.exram_enable = data == 0x0A,
+						0x2000..=0x2FFF => {
+							self.rom_bank = self.rom_bank & 0x100 | data as usize;
+							debug!(target: "cart", "ROM BANK {:03x}", self.rom_bank);
+						}
+						0x3000..=0x3FFF => {
+							self.rom_bank = self.rom_bank & 0xFF | (data as usize & 1) << 8;
+							debug!(target: "cart", "ROM BANK {:03x}", self.rom_bank);
+						}
+						0x4000..=0x5FFF if self.has_rumble => {
+							// the motor is wired to bit 3 in place of a RAM bank bit
+							self.exram_bank = data as usize & 0x7;
+							self.rumble = data & 0x8 != 0;
+							self.rumble_seen |= self.rumble;
+						}
+						0x4000..=0x5FFF => {
+							self.exram_bank = data as usize & 0xF;
+							debug!(target: "cart", "EXRAM BANK {:02x}", data);
 						}
+						_ => {}
 					},
 				}
 			}
 			// external ram bank N
 			0xA000..=0xBFFF => match self.mbc {
 				MBCType::MBC0 => {}
-				MBCType::MBC1 => {
-					if self.exram_enable {
+				MBCType::MBC1 | MBCType::MBC5 => {
+					if self.exram_enable && self.exram_bank < self.exram.len() {
 						self.exram[self.exram_bank][addr - 0xA000] = data;
 					}
 				}
@@ -0,0 +1,72 @@
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+
+// Code/data log: one byte of flags per ROM byte, saved next to the ROM as
+// .cdl and merged with what earlier sessions recorded.
+
+pub const CODE: u8 = 0x01; // executed as an opcode
+pub const OPERAND: u8 = 0x02; // read as an instruction operand
+pub const DATA: u8 = 0x04; // read by other instructions
+pub const DMA: u8 = 0x08; // OAM DMA source
+
+pub struct Cdl {
+	pub flags: Vec<u8>,
+	path: PathBuf,
+}
+impl Cdl {
+	/// Start logging a ROM, keeping the log from earlier sessions if it matches
+	pub fn open(rom_path: &str, rom_len: usize) -> Cdl {
+		let path = Path::new(rom_path).with_extension("cdl");
+		let flags = match std::fs::read(&path) {
+			Ok(flags) if flags.len() == rom_len => {
+				info!(target: "tools", "Loaded code/data log {}", path.display());
+				flags
+			}
+			Ok(_) => {
+				warn!(
+					target: "tools",
+					"Ignoring {}, it is for a different ROM size",
+					path.display()
+				);
+				vec![0; rom_len]
+			}
+			Err(_) => vec![0; rom_len],
+		};
+		Cdl { flags, path }
+	}
+
+	/// Load the log next to a ROM without recording anything
+	pub fn load_for_rom(rom_path: &str) -> Option<Cdl> {
+		let path = Path::new(rom_path).with_extension("cdl");
+		let flags = std::fs::read(&path).ok()?;
+		Some(Cdl { flags, path })
+	}
+
+	pub fn mark(&mut self, rom_offset: usize, usage: u8) {
+		if let Some(flags) = self.flags.get_mut(rom_offset) {
+			*flags |= usage;
+		}
+	}
+
+	pub fn get(&self, rom_offset: usize) -> u8 {
+		self.flags.get(rom_offset).copied().unwrap_or_default()
+	}
+
+	/// Read as data but never executed
+	pub fn is_data(&self, rom_offset: usize) -> bool {
+		let flags = self.get(rom_offset);
+		flags & (DATA | DMA) != 0 && flags & (CODE | OPERAND) == 0
+	}
+
+	pub fn save(&self) -> std::io::Result<()> {
+		std::fs::write(&self.path, &self.flags)?;
+		let covered = self.flags.iter().filter(|&&f| f != 0).count();
+		info!(
+			target: "tools",
+			"Wrote code/data log {} ({covered} of {} bytes used)",
+			self.path.display(),
+			self.flags.len()
+		);
+		Ok(())
+	}
+}
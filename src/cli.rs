@@ -0,0 +1,344 @@
+use crate::iolog::Filter;
+use crate::model::Model;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+// Command line parsing. Running a ROM is the default subcommand, so
+// "gameboy game.gb" and "gameboy run game.gb" do the same thing.
+
+pub const USAGE: &str = "\
+usage: gameboy [run] [options] <rom>
+       gameboy info <rom>
+       gameboy disasm <rom> [bank] [start-end]
+       gameboy test [options] <rom>
+       gameboy help";
+
+pub const HELP: &str = "\
+Subcommands:
+  run                    play a ROM (the default)
+  info                   show the cartridge header
+  disasm                 disassemble ROM banks, using .sym and .cdl files next to the ROM
+  test                   run a test ROM without a window and report pass or fail,
+                         from blargg serial output or the mooneye LD B,B signature
+
+Options:
+  --config <file>        settings file, $XDG_CONFIG_HOME/gameboy/config.toml by default
+  --boot-rom <file>      run this boot ROM instead of skipping it
+  --model <name>         dmg, mgb, sgb, cgb or agb, from the header by default
+  -g, -s                 shorthand for --model cgb and --model sgb
+  --scale <n>            window scale, 3 by default
+  --headless             no window or sound, running as fast as possible
+  --frames <n>           exit after n frames
+  --save-dir <dir>       where battery saves go, next to the ROM by default
+  --mute                 no sound
+  --record <file>        record the input to a movie, starting from power-on
+  --play <file>          play a movie back, stopping at its end when headless.
+                         Battery saves are neither loaded nor written with these.
+                         Movies can't start from a save state yet, only power-on
+
+Debugging:
+  -d, --debug            pause at start and read debugger commands from the terminal
+  --gdb <port>           serve GDB on a localhost port, paused until it connects
+  --log <levels>         log levels like warn,cpu=trace,io=fatal (also GB_LOG)
+                         targets: cpu, ppu, apu, timer, cart, io, sgb, ui, tools
+  -c, -i, -b             shorthand for --log cpu=trace, io=trace and cart=debug
+  --trace <file>         write a Gameboy Doctor style trace
+  --trace-range <s-e>    only trace with PC in this hex range
+  --trace-count <n>      stop tracing after n instructions
+  --doctor               make LY read 0x90, as Gameboy Doctor expects
+  --io-log <file>        log IO register accesses
+  --io-log-range <s-e>   log this hex address range instead
+  --io-log-reg <names>   only these registers, like LCDC,STAT
+  --io-log-kind <kind>   r, w or rw
+  --io-log-frames <s-e>  only in these frames
+  --profile <file>       print where cycles went at exit, with collapsed stacks in file
+  --cdl                  record a code/data log next to the ROM
+
+The config file sets defaults that options override:
+  scale = 4
+  volume = 0.5                  # 0 to 1
+  palette = \"classic\"           # grayscale, classic, pocket, light or high-contrast
+  save_dir = \"~/gb/saves\"
+  [keys]                        # a, b, select, start, right, left, up, down
+  a = \"X\"
+  b = [\"Z\", \"Backspace\"]
+  [gamepad]                     # dpad_up, north, east, south, west, l1, r1, back, start...
+  a = \"east\"
+  deadzone = 0.3                # for the analog stick
+  rumble = 1.0                  # 0 to 1, for rumble cartridges
+  [boot_roms]                   # by model
+  dmg = \"~/gb/dmg_boot.bin\"";
+
+pub enum Command {
+	Run(Options),
+	Info(String),
+	Disasm {
+		rom: String,
+		bank: Option<usize>,
+		range: Option<(u16, u16)>,
+	},
+	Test(Options),
+	Help,
+}
+
+#[derive(Default)]
+pub struct Options {
+	pub rom: String,
+	pub config: Option<String>,
+	pub boot_rom: Option<String>,
+	pub model: Option<Model>,
+	pub scale: Option<i32>,
+	pub headless: bool,
+	pub frames: Option<u64>,
+	pub save_dir: Option<PathBuf>,
+	pub mute: bool,
+
+	pub debug: bool,
+	pub gdb: Option<u16>,
+	pub log: Vec<String>,
+	pub trace: Option<String>,
+	pub trace_range: Option<RangeInclusive<u16>>,
+	pub trace_count: Option<u64>,
+	pub doctor: bool,
+	pub io_log: Option<String>,
+	pub io_log_filter: Filter,
+	pub profile: Option<String>,
+	pub cdl: bool,
+	pub record: Option<String>,
+	pub play: Option<String>,
+}
+
+pub fn parse(args: &[String]) -> Result<Command, String> {
+	match args.first().map(String::as_str) {
+		None => Err("No ROM given".into()),
+		Some("help" | "-h" | "--help") => Ok(Command::Help),
+		Some("run") => Ok(Command::Run(parse_options(&args[1..])?)),
+		Some("test") => Ok(Command::Test(parse_options(&args[1..])?)),
+		Some("info") => match &args[1..] {
+			[rom] => Ok(Command::Info(rom.clone())),
+			_ => Err("info needs exactly one ROM".into()),
+		},
+		Some("disasm") => parse_disasm(&args[1..]),
+		Some(_) => Ok(Command::Run(parse_options(args)?)),
+	}
+}
+
+fn parse_disasm(args: &[String]) -> Result<Command, String> {
+	let rom = args.first().ok_or("disasm needs a ROM")?.clone();
+	let bank = match args.get(1) {
+		Some(bank) => Some(parse_hex(bank)? as usize),
+		None => None,
+	};
+	let range = match args.get(2) {
+		Some(range) => {
+			let range = parse_hex_range(range)?;
+			Some((*range.start(), *range.end()))
+		}
+		None => None,
+	};
+	if args.len() > 3 {
+		return Err(format!("Unexpected argument {}", args[3]));
+	}
+	Ok(Command::Disasm { rom, bank, range })
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+	let mut options = Options::default();
+	let mut io_log_option = None; // an --io-log-* option, which needs --io-log
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		let mut value = || {
+			args.next()
+				.map(String::as_str)
+				.ok_or(format!("{arg} needs a value"))
+		};
+		match arg.as_str() {
+			"--config" => options.config = Some(value()?.into()),
+			"--boot-rom" => options.boot_rom = Some(value()?.into()),
+			"--model" => {
+				let name = value()?;
+				options.model = Some(Model::from_name(name).ok_or(format!(
+					"Unknown model {name}, expected dmg, mgb, sgb, cgb or agb"
+				))?);
+			}
+			"--scale" => {
+				options.scale = match value()?.parse() {
+					Ok(scale @ 1..=10) => Some(scale),
+					_ => return Err("--scale needs a number from 1 to 10".into()),
+				}
+			}
+			"--headless" => options.headless = true,
+			"--frames" => options.frames = Some(parse_number(arg, value()?)?),
+			"--save-dir" => options.save_dir = Some(value()?.into()),
+			"--mute" => options.mute = true,
+
+			"-d" | "--debug" => options.debug = true,
+			"--gdb" => options.gdb = Some(parse_number(arg, value()?)?),
+			"--log" => options.log.push(value()?.into()),
+			"--trace" => options.trace = Some(value()?.into()),
+			"--trace-range" => options.trace_range = Some(parse_hex_range(value()?)?),
+			"--trace-count" => options.trace_count = Some(parse_number(arg, value()?)?),
+			"--doctor" => options.doctor = true,
+			"--io-log" => options.io_log = Some(value()?.into()),
+			"--io-log-range" => {
+				options.io_log_filter.range = Some(parse_hex_range(value()?)?);
+				io_log_option = Some(arg);
+			}
+			"--io-log-reg" => {
+				options.io_log_filter.names = value()?.split(',').map(String::from).collect();
+				io_log_option = Some(arg);
+			}
+			"--io-log-kind" => {
+				options.io_log_filter.set_kind(value()?)?;
+				io_log_option = Some(arg);
+			}
+			"--io-log-frames" => {
+				let range = value()?;
+				let (start, end) = range
+					.split_once('-')
+					.ok_or(format!("{arg} needs start-end"))?;
+				options.io_log_filter.frames =
+					Some(parse_number(arg, start)?..=parse_number(arg, end)?);
+				io_log_option = Some(arg);
+			}
+			"--profile" => options.profile = Some(value()?.into()),
+			"--cdl" => options.cdl = true,
+			"--record" => options.record = Some(value()?.into()),
+			"--play" => options.play = Some(value()?.into()),
+
+			flag if flag.starts_with("--") => return Err(format!("Unknown option {flag}")),
+			flags if flags.starts_with('-') && flags.len() > 1 => {
+				for flag in flags.chars().skip(1) {
+					match flag {
+						'c' => options.log.push("cpu=trace".into()),
+						'i' => options.log.push("io=trace".into()),
+						'b' => options.log.push("cart=debug".into()),
+						'd' => options.debug = true,
+						'g' => options.model = Some(Model::CGB),
+						's' => options.model = Some(Model::SGB),
+						_ => return Err(format!("Unknown option -{flag}")),
+					}
+				}
+			}
+			rom if options.rom.is_empty() => options.rom = rom.into(),
+			other => {
+				return Err(format!(
+					"Unexpected argument {other}, the ROM is {}",
+					options.rom
+				));
+			}
+		}
+	}
+
+	if options.rom.is_empty() {
+		return Err("No ROM given".into());
+	}
+	if options.trace.is_none() && (options.trace_range.is_some() || options.trace_count.is_some()) {
+		return Err("--trace-range and --trace-count need --trace".into());
+	}
+	if options.record.is_some() && options.play.is_some() {
+		return Err("--record and --play can't be used together".into());
+	}
+	if let Some(option) = io_log_option
+		&& options.io_log.is_none()
+	{
+		return Err(format!("{option} needs --io-log"));
+	}
+	Ok(options)
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, s: &str) -> Result<T, String> {
+	s.parse()
+		.map_err(|_| format!("{option} needs a number, not {s}"))
+}
+
+fn parse_hex(s: &str) -> Result<u16, String> {
+	let digits = s.trim_start_matches('$').trim_start_matches("0x");
+	u16::from_str_radix(digits, 16).map_err(|_| format!("Bad hex number {s}"))
+}
+
+fn parse_hex_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+	let (start, end) = s
+		.split_once('-')
+		.ok_or(format!("Expected a hex range like 4000-7fff, not {s}"))?;
+	Ok(parse_hex(start)?..=parse_hex(end)?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_str(args: &str) -> Result<Command, String> {
+		parse(
+			&args
+				.split_whitespace()
+				.map(String::from)
+				.collect::<Vec<_>>(),
+		)
+	}
+
+	fn error_of(args: &str) -> String {
+		parse_str(args).err().expect("should fail")
+	}
+
+	#[test]
+	fn run_options() {
+		let Ok(Command::Run(options)) = parse_str("run --headless --frames 60 -ds game.gb") else {
+			panic!("not a run");
+		};
+		assert_eq!(options.rom, "game.gb");
+		assert!(options.headless && options.debug);
+		assert_eq!(options.frames, Some(60));
+		assert_eq!(options.model, Some(Model::SGB));
+	}
+
+	#[test]
+	fn run_is_the_default() {
+		assert!(matches!(parse_str("game.gb --mute"), Ok(Command::Run(o)) if o.mute));
+	}
+
+	#[test]
+	fn disasm_range() {
+		let Ok(Command::Disasm { rom, bank, range }) = parse_str("disasm game.gb 1 $4000-7fff")
+		else {
+			panic!("not a disasm");
+		};
+		assert_eq!(rom, "game.gb");
+		assert_eq!(bank, Some(1));
+		assert_eq!(range, Some((0x4000, 0x7FFF)));
+	}
+
+	#[test]
+	fn unknown_option() {
+		assert_eq!(error_of("--turbo game.gb"), "Unknown option --turbo");
+		assert_eq!(error_of("-dx game.gb"), "Unknown option -x");
+	}
+
+	#[test]
+	fn bad_hex_range() {
+		let err = error_of("--trace t.log --trace-range 4000 game.gb");
+		assert!(err.starts_with("Expected a hex range"), "{err}");
+		assert_eq!(
+			error_of("--trace t.log --trace-range 40zz-7fff game.gb"),
+			"Bad hex number 40zz"
+		);
+	}
+
+	#[test]
+	fn options_that_need_others() {
+		assert_eq!(
+			error_of("--trace-count 10 game.gb"),
+			"--trace-range and --trace-count need --trace"
+		);
+		assert_eq!(
+			error_of("--io-log-kind w game.gb"),
+			"--io-log-kind needs --io-log"
+		);
+		assert_eq!(
+			error_of("--record a.gbm --play b.gbm game.gb"),
+			"--record and --play can't be used together"
+		);
+		assert_eq!(error_of("game.gb --frames"), "--frames needs a value");
+		assert_eq!(error_of("--headless"), "No ROM given");
+	}
+}
@@ -0,0 +1,187 @@
+use crate::bus::Bus;
+
+// Colorization of DMG games by the CGB boot ROM.
+// The boot ROM hashes the cartridge title, looks the hash up in a table of
+// (mostly first-party) games, and loads BG palette 0 and OBJ palettes 0/1.
+// Holding a direction (optionally with A or B) during the logo overrides it.
+
+// Index of the first checksum that is shared by more than one game.
+// From here on the 4th letter of the title is compared as well.
+const FIRST_DUPLICATE: usize = 65;
+
+const TITLE_CHECKSUMS: [u8; 94] = [
+	0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+	0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+	0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+	0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+	0x6B, //
+	// duplicates, disambiguated by DUPLICATE_4TH_LETTERS
+	0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3, 0x46,
+	0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+const DUPLICATE_4TH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette combination for each entry of TITLE_CHECKSUMS
+const TITLE_COMBINATIONS: [u8; 94] = [
+	0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+	5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+	5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, //
+	36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19,
+	34, 23, 18, 29,
+];
+
+// Combinations selected by holding a direction, direction+A, or direction+B
+// (joypad io_pin, combination, combination with A, combination with B)
+const BUTTON_COMBINATIONS: &[(u8, u8, u8, u8)] = &[
+	(1, 1, 0, 6),   // RIGHT: green, dark green (default), inverted
+	(2, 48, 40, 7), // LEFT: blue, dark blue, grayscale
+	(4, 5, 43, 28), // UP: brown, red, dark brown
+	(8, 8, 3, 49),  // DOWN: pastel mix, orange, yellow
+];
+
+// Offsets into PALETTES, counted in colors: (OBJ0, OBJ1, BG)
+// Most point at the start of a palette, but a few straddle two of them.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+	(4 * 4, 4 * 4, 29 * 4),
+	(18 * 4, 18 * 4, 18 * 4),
+	(20 * 4, 20 * 4, 20 * 4),
+	(24 * 4, 24 * 4, 24 * 4),
+	(9 * 4, 9 * 4, 9 * 4),
+	(0, 0, 0),
+	(27 * 4, 27 * 4, 27 * 4),
+	(5 * 4, 5 * 4, 5 * 4),
+	(12 * 4, 12 * 4, 12 * 4),
+	(26 * 4, 26 * 4, 26 * 4),
+	(16 * 4, 8 * 4, 8 * 4),
+	(4 * 4, 28 * 4, 28 * 4),
+	(4 * 4, 2 * 4, 2 * 4),
+	(3 * 4, 4 * 4, 4 * 4),
+	(4 * 4, 29 * 4, 29 * 4),
+	(28 * 4, 4 * 4, 28 * 4),
+	(2 * 4, 17 * 4, 2 * 4),
+	(16 * 4, 16 * 4, 8 * 4),
+	(4 * 4, 4 * 4, 7 * 4),
+	(4 * 4, 4 * 4, 18 * 4),
+	(4 * 4, 4 * 4, 20 * 4),
+	(19 * 4, 19 * 4, 9 * 4),
+	(4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+	(17 * 4, 17 * 4, 2 * 4),
+	(4 * 4, 4 * 4, 2 * 4),
+	(4 * 4, 4 * 4, 3 * 4),
+	(28 * 4, 28 * 4, 0),
+	(3 * 4, 3 * 4, 0),
+	(0, 0, 4),
+	(18 * 4, 22 * 4, 18 * 4),
+	(20 * 4, 22 * 4, 20 * 4),
+	(24 * 4, 22 * 4, 24 * 4),
+	(16 * 4, 22 * 4, 8 * 4),
+	(17 * 4, 4 * 4, 13 * 4),
+	(28 * 4 - 1, 0, 14 * 4),
+	(28 * 4 - 1, 4 * 4, 15 * 4),
+	(19 * 4, 22 * 4, 9 * 4),
+	(16 * 4, 28 * 4, 10 * 4),
+	(4 * 4, 23 * 4, 28 * 4),
+	(17 * 4, 22 * 4, 2 * 4),
+	(4 * 4, 0, 2 * 4),
+	(4 * 4, 28 * 4, 3 * 4),
+	(28 * 4, 3 * 4, 0),
+	(3 * 4, 28 * 4, 4 * 4),
+	(21 * 4, 28 * 4, 4 * 4),
+	(3 * 4, 28 * 4, 0),
+	(25 * 4, 3 * 4, 28 * 4),
+	(0, 28 * 4, 8 * 4),
+	(4 * 4, 3 * 4, 28 * 4),
+	(28 * 4, 3 * 4, 6 * 4),
+	(4 * 4, 28 * 4, 29 * 4),
+];
+
+// RGB555, 4 colors per palette
+const PALETTES: [u16; 30 * 4] = [
+	0x7FFF, 0x32BF, 0x00D0, 0x0000, // 0
+	0x639F, 0x4279, 0x15B0, 0x04CB, // 1
+	0x7FFF, 0x6E31, 0x454A, 0x0000, // 2
+	0x7FFF, 0x1BEF, 0x0200, 0x0000, // 3
+	0x7FFF, 0x421F, 0x1CF2, 0x0000, // 4
+	0x7FFF, 0x5294, 0x294A, 0x0000, // 5
+	0x7FFF, 0x03FF, 0x012F, 0x0000, // 6
+	0x7FFF, 0x03EF, 0x01D6, 0x0000, // 7
+	0x7FFF, 0x42B5, 0x3DC8, 0x0000, // 8
+	0x7E74, 0x03FF, 0x0180, 0x0000, // 9
+	0x67FF, 0x77AC, 0x1A13, 0x2D6B, // 10
+	0x7ED6, 0x4BFF, 0x2175, 0x0000, // 11
+	0x53FF, 0x4A5F, 0x7E52, 0x0000, // 12
+	0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, // 13
+	0x03ED, 0x7FFF, 0x255F, 0x0000, // 14
+	0x036A, 0x021F, 0x03FF, 0x7FFF, // 15
+	0x7FFF, 0x01DF, 0x0112, 0x0000, // 16
+	0x231F, 0x035F, 0x00F2, 0x0009, // 17
+	0x7FFF, 0x03EA, 0x011F, 0x0000, // 18
+	0x299F, 0x001A, 0x000C, 0x0000, // 19
+	0x7FFF, 0x027F, 0x001F, 0x0000, // 20
+	0x7FFF, 0x03E0, 0x0206, 0x0120, // 21
+	0x7FFF, 0x7EEB, 0x001F, 0x7C00, // 22
+	0x7FFF, 0x3FFF, 0x7E00, 0x001F, // 23
+	0x7FFF, 0x03FF, 0x001F, 0x0000, // 24
+	0x03FF, 0x001F, 0x000C, 0x0000, // 25
+	0x7FFF, 0x033F, 0x0193, 0x0000, // 26
+	0x0000, 0x4200, 0x037F, 0x7FFF, // 27
+	0x7FFF, 0x7E8C, 0x7C00, 0x0000, // 28
+	0x7FFF, 0x1BEF, 0x6180, 0x0000, // 29
+];
+
+fn title_combination(header: &[u8]) -> u8 {
+	// Only games published by Nintendo get a custom palette
+	let licensee_nintendo = match header[0x14B] {
+		0x01 => true,
+		0x33 => header[0x144..=0x145] == *b"01",
+		_ => false,
+	};
+	if !licensee_nintendo {
+		return 0;
+	}
+
+	let checksum = header[0x134..=0x143]
+		.iter()
+		.fold(0u8, |acc, x| acc.wrapping_add(*x));
+
+	for (i, table_checksum) in TITLE_CHECKSUMS.iter().enumerate() {
+		if *table_checksum != checksum {
+			continue;
+		}
+		if i < FIRST_DUPLICATE || DUPLICATE_4TH_LETTERS[i - FIRST_DUPLICATE] == header[0x137] {
+			return TITLE_COMBINATIONS[i];
+		}
+	}
+	0
+}
+
+fn button_combination(joypad: u8, buttons: u8) -> Option<u8> {
+	for (io_pin, plain, with_a, with_b) in BUTTON_COMBINATIONS {
+		if joypad & io_pin != 0 {
+			return Some(match buttons & 0b11 {
+				0b01 => *with_a,
+				0b10 => *with_b,
+				_ => *plain,
+			});
+		}
+	}
+	None
+}
+
+fn load_palette(ram: &mut [u8; 8], color_ofs: usize) {
+	for (i, color) in PALETTES[color_ofs..color_ofs + 4].iter().enumerate() {
+		ram[i * 2..i * 2 + 2].copy_from_slice(&color.to_le_bytes());
+	}
+}
+
+/// Load the CGB palettes the boot ROM would pick for the inserted DMG cartridge.
+pub fn colorize(bus: &mut Bus) {
+	let combination = button_combination(bus.io.user_input_joypad, bus.io.user_input_buttons)
+		.unwrap_or_else(|| title_combination(&bus.cart.rom[0]));
+
+	let (obj0, obj1, bg) = COMBINATIONS[combination as usize];
+	load_palette(&mut bus.io.bg_palette_ram[0], bg);
+	load_palette(&mut bus.io.obj_palette_ram[0], obj0);
+	load_palette(&mut bus.io.obj_palette_ram[1], obj1);
+}
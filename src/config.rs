@@ -0,0 +1,294 @@
+use crate::model::Model;
+use crate::{ui, video};
+use log::info;
+use raylib::prelude::{GamepadButton, KeyboardKey};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// Settings from a TOML file, by default $XDG_CONFIG_HOME/gameboy/config.toml.
+// Command line options override them.
+//
+//   scale = 4
+//   volume = 0.5
+//   palette = "classic"
+//   save_dir = "~/gb/saves"
+//
+//   [keys]
+//   a = "X"
+//   b = ["Z", "Backspace"]
+//
+//   [gamepad]
+//   a = "south"
+//   b = "west"
+//   deadzone = 0.4
+//   rumble = 0.5
+//
+//   [boot_roms]
+//   dmg = "~/gb/dmg_boot.bin"
+
+pub const DEFAULT_VOLUME: f32 = 1.0;
+pub const DEFAULT_DEADZONE: f32 = 0.3;
+
+// (name, is_joypad, io_pin), as in ui::CONTROLS
+pub const BUTTONS: [(&str, bool, u8); 8] = [
+	("a", false, 1),
+	("b", false, 2),
+	("select", false, 4),
+	("start", false, 8),
+	("right", true, 1),
+	("left", true, 2),
+	("up", true, 4),
+	("down", true, 8),
+];
+
+pub struct Config {
+	pub keys: Vec<(bool, u8, KeyboardKey)>,
+	pub gamepad: Vec<(bool, u8, GamepadButton)>,
+	pub deadzone: f32, // how far the analog stick moves before it counts
+	pub rumble: f32,   // strength, 0 for none
+	pub scale: i32,
+	pub volume: f32,
+	pub palette: usize, // index into video::PRESETS
+	pub save_dir: Option<PathBuf>,
+	pub boot_roms: Vec<(Model, PathBuf)>,
+}
+impl std::default::Default for Config {
+	fn default() -> Config {
+		Config {
+			keys: ui::CONTROLS.to_vec(),
+			gamepad: ui::GAMEPAD_CONTROLS.to_vec(),
+			deadzone: DEFAULT_DEADZONE,
+			rumble: 1.0,
+			scale: 3,
+			volume: DEFAULT_VOLUME,
+			palette: 0,
+			save_dir: None,
+			boot_roms: vec![],
+		}
+	}
+}
+impl Config {
+	/// Load the given file, or the default one if it exists
+	pub fn load(path: Option<&str>) -> Result<Config, String> {
+		let (path, text) = match path {
+			Some(path) => {
+				let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+				(PathBuf::from(path), text)
+			}
+			None => {
+				let Some(path) = default_path() else {
+					return Ok(Config::default());
+				};
+				match std::fs::read_to_string(&path) {
+					Ok(text) => (path, text),
+					Err(_) => return Ok(Config::default()),
+				}
+			}
+		};
+		let config = Config::parse(&text).map_err(|e| format!("{}: {e}", path.display()))?;
+		info!(target: "ui", "Loaded config {}", path.display());
+		Ok(config)
+	}
+
+	pub fn parse(text: &str) -> Result<Config, String> {
+		let file: ConfigFile = toml::from_str(text).map_err(|e| e.to_string())?;
+		let mut config = Config::default();
+		if let Some(scale) = file.scale {
+			if !(1..=10).contains(&scale) {
+				return Err("scale must be a number from 1 to 10".into());
+			}
+			config.scale = scale;
+		}
+		if let Some(volume) = file.volume {
+			config.volume = fraction("volume", volume)?;
+		}
+		if let Some(name) = file.palette {
+			config.palette = video::PRESETS
+				.iter()
+				.position(|(preset, _)| preset.eq_ignore_ascii_case(&name))
+				.ok_or_else(|| {
+					let names: Vec<&str> = video::PRESETS.iter().map(|p| p.0).collect();
+					format!(
+						"Unknown palette {name}, expected one of {}",
+						names.join(", ")
+					)
+				})?;
+		}
+		config.save_dir = file.save_dir.as_deref().map(expand_home);
+		for (button, names) in file.keys {
+			bind(&mut config.keys, &button, names, |name| {
+				ui::key_from_name(name).ok_or(format!("Unknown key {name}"))
+			})?;
+		}
+		for (button, value) in file.gamepad {
+			match (button.as_str(), value) {
+				("deadzone", GamepadValue::Number(n)) => config.deadzone = fraction("deadzone", n)?,
+				("rumble", GamepadValue::Number(n)) => config.rumble = fraction("rumble", n)?,
+				("deadzone" | "rumble", _) => return Err(format!("{button} must be a number")),
+				(_, GamepadValue::Number(_)) => {
+					return Err(format!("Expected a gamepad button name for {button}"));
+				}
+				(_, GamepadValue::Names(names)) => {
+					bind(&mut config.gamepad, &button, names, |name| {
+						ui::gamepad_button_from_name(name)
+							.ok_or(format!("Unknown gamepad button {name}"))
+					})?
+				}
+			}
+		}
+		for (name, path) in file.boot_roms {
+			let model = Model::from_name(&name).ok_or(format!("Unknown model {name}"))?;
+			config.boot_roms.push((model, expand_home(&path)));
+		}
+		Ok(config)
+	}
+
+	pub fn boot_rom(&self, model: Model) -> Option<&Path> {
+		self.boot_roms
+			.iter()
+			.find(|(m, _)| *m == model)
+			.map(|(_, path)| path.as_path())
+	}
+}
+
+// The file as written, before names are looked up
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+	scale: Option<i32>,
+	volume: Option<f32>,
+	palette: Option<String>,
+	save_dir: Option<String>,
+	#[serde(default)]
+	keys: BTreeMap<String, Names>,
+	#[serde(default)]
+	gamepad: BTreeMap<String, GamepadValue>,
+	#[serde(default)]
+	boot_roms: BTreeMap<String, String>,
+}
+
+// One input or an array of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Names {
+	One(String),
+	Many(Vec<String>),
+}
+
+// [gamepad] holds the stick and rumble settings next to the buttons
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GamepadValue {
+	Number(f32),
+	Names(Names),
+}
+
+// Bind a Game Boy button to its inputs, replacing the defaults
+fn bind<T>(
+	bindings: &mut Vec<(bool, u8, T)>,
+	button: &str,
+	names: Names,
+	input: impl Fn(&str) -> Result<T, String>,
+) -> Result<(), String> {
+	let &(_, is_joypad, pin) = BUTTONS
+		.iter()
+		.find(|b| b.0 == button)
+		.ok_or(format!("Unknown button {button}"))?;
+	let names = match names {
+		Names::One(name) => vec![name],
+		Names::Many(names) => names,
+	};
+	bindings.retain(|&(joypad, io_pin, _)| (joypad, io_pin) != (is_joypad, pin));
+	for name in names {
+		bindings.push((is_joypad, pin, input(&name)?));
+	}
+	Ok(())
+}
+
+/// A number from 0 to 1
+fn fraction(setting: &str, n: f32) -> Result<f32, String> {
+	match (0.0..=1.0).contains(&n) {
+		true => Ok(n),
+		false => Err(format!("{setting} must be from 0 to 1")),
+	}
+}
+
+fn default_path() -> Option<PathBuf> {
+	let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+		Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+		_ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+	};
+	Some(dir.join("gameboy").join("config.toml"))
+}
+
+fn expand_home(path: &str) -> PathBuf {
+	match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+		(Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+		_ => PathBuf::from(path),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn settings() {
+		let config = Config::parse(
+			r#"
+			scale = 4
+			volume = 0.5
+			palette = "Pocket"
+			[keys]
+			a = ["Z", "Space"]
+			[gamepad]
+			deadzone = 0.4
+			[boot_roms]
+			dmg = "dmg_boot.bin"
+			"#,
+		)
+		.unwrap();
+		assert_eq!(config.scale, 4);
+		assert_eq!(config.volume, 0.5);
+		assert_eq!(video::PRESETS[config.palette].0, "pocket");
+		let a: Vec<KeyboardKey> = config
+			.keys
+			.iter()
+			.filter(|&&(joypad, pin, _)| (joypad, pin) == (false, 1))
+			.map(|b| b.2)
+			.collect();
+		assert_eq!(a, [KeyboardKey::KEY_Z, KeyboardKey::KEY_SPACE]);
+		assert_eq!(config.deadzone, 0.4);
+		assert_eq!(config.boot_rom(Model::DMG), Some(Path::new("dmg_boot.bin")));
+	}
+
+	#[test]
+	fn empty_file_is_the_defaults() {
+		let config = Config::parse("").unwrap();
+		assert_eq!(config.keys, ui::CONTROLS);
+		assert_eq!(config.scale, 3);
+	}
+
+	#[test]
+	fn unknown_key() {
+		let err = Config::parse("[keys]\na = \"Hyperdrive\"").err().unwrap();
+		assert_eq!(err, "Unknown key Hyperdrive");
+		let err = Config::parse("[keys]\nturbo = \"X\"").err().unwrap();
+		assert_eq!(err, "Unknown button turbo");
+	}
+
+	#[test]
+	fn unknown_setting() {
+		let err = Config::parse("speed = 2").err().unwrap();
+		assert!(err.contains("unknown field `speed`"), "{err}");
+	}
+
+	#[test]
+	fn out_of_range() {
+		let err = Config::parse("scale = 11").err().unwrap();
+		assert_eq!(err, "scale must be a number from 1 to 10");
+		let err = Config::parse("[gamepad]\nrumble = 2.0").err().unwrap();
+		assert_eq!(err, "rumble must be from 0 to 1");
+	}
+}
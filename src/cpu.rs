@@ -1,5 +1,10 @@
 use crate::GB;
 use crate::bus;
+use crate::callstack::{CallStack, Frame};
+use crate::cdl;
+use crate::disasm;
+use crate::trace::Trace;
+use log::{Level, debug, error, log_enabled, trace, warn};
 
 #[derive(Default)]
 pub struct Flags {
@@ -8,6 +13,19 @@ pub struct Flags {
 	pub h: bool,
 	pub c: bool,
 }
+impl Flags {
+	pub fn bits(&self) -> u8 {
+		(self.z as u8) << 7 | (self.n as u8) << 6 | (self.h as u8) << 5 | (self.c as u8) << 4
+	}
+	pub fn from_bits(bits: u8) -> Flags {
+		Flags {
+			z: bits & 0x80 != 0,
+			n: bits & 0x40 != 0,
+			h: bits & 0x20 != 0,
+			c: bits & 0x10 != 0,
+		}
+	}
+}
 
 #[derive(Default)]
 pub struct CPU {
@@ -26,9 +44,17 @@ pub struct CPU {
 	pub ime_soon: bool,
 
 	pub halt: bool,
-	pub debug: bool,
+	pub halt_bug: bool,
+	pub stopped: bool,           // in STOP until a button is pressed
+	pub locked: Option<u16>,     // PC of the illegal opcode that hung the CPU
+	pub dispatched: Option<u16>, // interrupt vector entered by the last cycle
+	pub trace: Option<Trace>,
+	pub calls: CallStack,
 }
 impl CPU {
+	pub fn get_af(&self) -> u16 {
+		(self.a as u16) << 8 | self.f.bits() as u16
+	}
 	pub fn get_bc(&self) -> u16 {
 		(self.b as u16) << 8 | (self.c as u16)
 	}
@@ -74,7 +100,7 @@ impl std::fmt::Debug for CPU {
 	}
 }
 
-fn get_r8(cpu: &CPU, mem: &bus::Bus, r8: u8) -> (u8, u64) {
+fn get_r8(cpu: &CPU, mem: &mut bus::Bus, r8: u8) -> (u8, u64) {
 	let data = match r8 {
 		0 => cpu.b,
 		1 => cpu.c,
@@ -82,7 +108,7 @@ fn get_r8(cpu: &CPU, mem: &bus::Bus, r8: u8) -> (u8, u64) {
 		3 => cpu.e,
 		4 => cpu.h,
 		5 => cpu.l,
-		6 => mem.peek(cpu.get_hl()),
+		6 => mem.read(cpu.get_hl()),
 		7 => cpu.a,
 		_ => panic!("get_r8 bad register {r8}"),
 	};
@@ -97,7 +123,7 @@ fn set_r8(cpu: &mut CPU, mem: &mut bus::Bus, r8: u8, n: u8) -> u64 {
 		3 => cpu.e = n,
 		4 => cpu.h = n,
 		5 => cpu.l = n,
-		6 => mem.poke(cpu.get_hl(), n),
+		6 => mem.write(cpu.get_hl(), n),
 		7 => cpu.a = n,
 		_ => panic!("set_r8 bad register {r8}"),
 	};
@@ -133,9 +159,9 @@ fn set_r16(cpu: &mut CPU, r16: u8, n: u16) {
 fn get_r16mem(cpu: &mut CPU, mem: &mut bus::Bus, r16: u8) {
 	assert!(r16 <= 3);
 	match r16 {
-		0 => cpu.a = mem.peek(cpu.get_bc()),
-		1 => cpu.a = mem.peek(cpu.get_de()),
-		_ => cpu.a = mem.peek(cpu.get_hl()),
+		0 => cpu.a = mem.read(cpu.get_bc()),
+		1 => cpu.a = mem.read(cpu.get_de()),
+		_ => cpu.a = mem.read(cpu.get_hl()),
 	}
 	if r16 == 2 {
 		cpu.set_hl(cpu.get_hl() + 1);
@@ -147,9 +173,9 @@ fn get_r16mem(cpu: &mut CPU, mem: &mut bus::Bus, r16: u8) {
 fn set_r16mem(cpu: &mut CPU, mem: &mut bus::Bus, r16: u8) {
 	assert!(r16 <= 3);
 	match r16 {
-		0 => mem.poke(cpu.get_bc(), cpu.a),
-		1 => mem.poke(cpu.get_de(), cpu.a),
-		_ => mem.poke(cpu.get_hl(), cpu.a),
+		0 => mem.write(cpu.get_bc(), cpu.a),
+		1 => mem.write(cpu.get_de(), cpu.a),
+		_ => mem.write(cpu.get_hl(), cpu.a),
 	}
 	if r16 == 2 {
 		cpu.set_hl(cpu.get_hl() + 1);
@@ -163,13 +189,7 @@ fn get_r16stk(cpu: &mut CPU, r16: u8) -> u16 {
 		0 => u16::from_le_bytes([cpu.c, cpu.b]),
 		1 => u16::from_le_bytes([cpu.e, cpu.d]),
 		2 => u16::from_le_bytes([cpu.l, cpu.h]),
-		3 => {
-			((cpu.a as u16) << 8)
-				| ((cpu.f.z as u16) << 7)
-				| ((cpu.f.n as u16) << 6)
-				| ((cpu.f.h as u16) << 5)
-				| ((cpu.f.c as u16) << 4)
-		}
+		3 => cpu.get_af(),
 		_ => panic!("set_r16 bad register {r16}"),
 	}
 }
@@ -193,6 +213,33 @@ fn set_r16stk(cpu: &mut CPU, r16: u8, n: u16) {
 	}
 }
 
+fn read_imm8(cpu: &CPU, mem: &mut bus::Bus) -> u8 {
+	mem.read_as(cpu.pc.wrapping_add(1), cdl::OPERAND)
+}
+
+fn read_imm16(cpu: &CPU, mem: &mut bus::Bus) -> u16 {
+	let lo = mem.read_as(cpu.pc.wrapping_add(1), cdl::OPERAND);
+	let hi = mem.read_as(cpu.pc.wrapping_add(2), cdl::OPERAND);
+	u16::from_le_bytes([lo, hi])
+}
+
+// Includes the internal M-cycle that comes before the writes
+fn push16(cpu: &mut CPU, mem: &mut bus::Bus, value: u16) {
+	mem.tick();
+	cpu.sp = cpu.sp.wrapping_sub(1);
+	mem.write(cpu.sp, (value >> 8) as u8);
+	cpu.sp = cpu.sp.wrapping_sub(1);
+	mem.write(cpu.sp, value as u8);
+}
+
+fn pop16(cpu: &mut CPU, mem: &mut bus::Bus) -> u16 {
+	let lo = mem.read(cpu.sp);
+	cpu.sp = cpu.sp.wrapping_add(1);
+	let hi = mem.read(cpu.sp);
+	cpu.sp = cpu.sp.wrapping_add(1);
+	u16::from_le_bytes([lo, hi])
+}
+
 fn get_cond(f: &Flags, cond: u8) -> bool {
 	match cond {
 		0 => !f.z,
@@ -203,6 +250,11 @@ fn get_cond(f: &Flags, cond: u8) -> bool {
 	}
 }
 
+// These hang the CPU until it is reset
+const ILLEGAL_OPCODES: [u8; 11] = [
+	0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+];
+
 fn u8_as_signed_ofs(ofs: u8) -> u16 {
 	let mut ofs = ofs as u16;
 	if ofs >= 0x80 {
@@ -214,44 +266,122 @@ fn u8_as_signed_ofs(ofs: u8) -> u16 {
 pub fn cycle(gb: &mut GB) -> u64 {
 	let cpu = &mut gb.cpu;
 	let mem = &mut gb.bus;
-	if cpu.halt {
+	let start = mem.mcycles;
+	cpu.dispatched = None;
+	if cpu.locked.is_some() {
+		mem.tick();
 		return 1;
 	}
+	// STOP ends when a selected button is pressed, whatever IE says
+	if cpu.stopped {
+		if mem.io.p1_inputs() == 0xF {
+			mem.tick_stopped();
+			return 1;
+		}
+		cpu.stopped = false;
+	}
 
-	let opcode = mem.peek(cpu.pc);
-
-	let imm8 = mem.peek(cpu.pc.wrapping_add(1));
-	let imm8_2 = mem.peek(cpu.pc.wrapping_add(2));
-	let imm16 = ((imm8_2 as u16) << 8) | (imm8 as u16);
-
-	if cpu.ime {
-		let inter = mem.io.interrupt & mem.io.ie & 0b11111;
-		if inter > 0 {
-			cpu.ime = false;
-			cpu.ime_soon = false;
-			cpu.sp -= 2;
-			mem.poke16(cpu.sp, cpu.pc);
-			for bit in 0..5 {
-				if inter & (1 << bit) > 0 {
-					if cpu.debug {
-						println!("triggering interrupt: {inter:08b}");
-					}
-					mem.io.interrupt &= !(1 << bit);
-					cpu.pc = 0x40 + 8 * bit;
-					return 5;
-				}
+	let pending = mem.io.interrupt & mem.io.ie & 0b11111;
+
+	// Any pending interrupt ends HALT, even when IME is off
+	if cpu.halt {
+		if pending == 0 {
+			mem.tick();
+			return 1;
+		}
+		cpu.halt = false;
+	}
+
+	if cpu.ime && pending > 0 {
+		cpu.ime = false;
+		cpu.ime_soon = false;
+		if cpu.halt_bug {
+			// EI, HALT: the interrupt returns to the HALT instruction
+			cpu.halt_bug = false;
+			cpu.pc = cpu.pc.wrapping_sub(1);
+		}
+
+		let interrupted = (mem.bank_at(cpu.pc), cpu.pc);
+
+		// M1-M2: internal delay
+		mem.tick();
+		mem.tick();
+
+		// M3: push PC high byte
+		cpu.sp = cpu.sp.wrapping_sub(1);
+		mem.write(cpu.sp, (cpu.pc >> 8) as u8);
+
+		// The interrupt is only chosen now, so the push above can cancel it
+		// by overwriting IE at 0xFFFF.
+		let pending = mem.io.interrupt & mem.io.ie & 0b11111;
+
+		// M4: push PC low byte
+		cpu.sp = cpu.sp.wrapping_sub(1);
+		mem.write(cpu.sp, cpu.pc as u8);
+
+		// M5: jump to the vector, or to 0x0000 if the interrupt was cancelled
+		debug!(target: "cpu", "triggering interrupt: {pending:08b}");
+		cpu.pc = match pending {
+			0 => 0x0000,
+			_ => {
+				let bit = pending.trailing_zeros() as u16;
+				mem.io.interrupt &= !(1 << bit);
+				0x40 + 8 * bit
 			}
-			panic!("Interrupt bug!");
+		};
+		cpu.dispatched = Some(cpu.pc);
+		cpu.calls.call(Frame {
+			call_site: interrupted,
+			target: (0, cpu.pc),
+			sp: cpu.sp,
+			interrupt: true,
+		});
+		mem.tick();
+		return 5;
+	}
+
+	if let Some(mut trace) = cpu.trace.take() {
+		match trace.log(cpu, mem) {
+			Ok(()) => cpu.trace = Some(trace),
+			Err(e) => warn!(target: "cpu", "Trace stopped: {e}"),
 		}
 	}
 
-	if cpu.debug {
-		println!(
-			"{cpu:>2x?} - {:x} {:x} {:x}",
-			mem.peek(cpu.pc.wrapping_add(0)),
-			mem.peek(cpu.pc.wrapping_add(1)),
-			mem.peek(cpu.pc.wrapping_add(2)),
+	cpu.calls.record(mem, cpu.pc);
+	let call_site = cpu.pc;
+	let sp_before = cpu.sp;
+
+	let opcode = mem.read_as(cpu.pc, cdl::CODE);
+	if ILLEGAL_OPCODES.contains(&opcode) {
+		error!(
+			target: "cpu",
+			"CPU locked up: illegal opcode {opcode:#04x} at {:#06x}\n{}",
+			cpu.pc,
+			cpu.calls.report(mem, cpu.pc)
 		);
+		cpu.locked = Some(cpu.pc);
+		// nothing more gets traced, so make sure the lines leading here are written
+		if let Some(trace) = &mut cpu.trace
+			&& let Err(e) = trace.flush()
+		{
+			warn!(target: "cpu", "Trace stopped: {e}");
+		}
+		return 1;
+	}
+	if cpu.halt_bug {
+		// PC failed to increment after HALT, so the next byte is read twice
+		cpu.halt_bug = false;
+		cpu.pc = cpu.pc.wrapping_sub(1);
+	}
+
+	if log_enabled!(target: "cpu", Level::Trace) {
+		let bank = mem.bank_at(cpu.pc);
+		match mem.symbols.describe(bank, cpu.pc) {
+			Some(label) => {
+				trace!(target: "cpu", "{cpu:>2x?} - {label}: {}", disasm::at(mem, cpu.pc).text)
+			}
+			None => trace!(target: "cpu", "{cpu:>2x?} - {}", disasm::at(mem, cpu.pc).text),
+		}
 	}
 
 	let mut ime_enabled_this_cycle = false;
@@ -268,22 +398,27 @@ pub fn cycle(gb: &mut GB) -> u64 {
 						// 00---000
 						0b00_000_000 => (1, 1),
 						0b00_001_000 => {
-							mem.poke16(imm16, cpu.sp);
+							let addr = read_imm16(cpu, mem);
+							mem.write(addr, cpu.sp as u8);
+							mem.write(addr.wrapping_add(1), (cpu.sp >> 8) as u8);
 							(3, 5)
 						}
 						0b00_010_000 => {
-							cpu.halt = true; // TODO: might want to add more here
-							todo!("SPEED SWITCH");
-							// (2, 1)
+							// DIV is reset and the CPU waits for a button press
+							mem.poke(0xFF04, 0);
+							cpu.stopped = true;
+							(2, 1)
 						}
 						0b00_011_000 => {
-							cpu.pc = cpu.pc.wrapping_add(u8_as_signed_ofs(imm8));
+							let ofs = read_imm8(cpu, mem);
+							cpu.pc = cpu.pc.wrapping_add(u8_as_signed_ofs(ofs));
 							(2, 3)
 						}
 						_ => {
 							// 0b001--000
+							let ofs = read_imm8(cpu, mem);
 							if get_cond(&cpu.f, (opcode >> 3) & 0b11) {
-								cpu.pc = cpu.pc.wrapping_add(u8_as_signed_ofs(imm8));
+								cpu.pc = cpu.pc.wrapping_add(u8_as_signed_ofs(ofs));
 								(2, 3)
 							} else {
 								(2, 2)
@@ -296,6 +431,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					if opcode & 0b1000 == 0 {
 						// 00--0001
 						let r16 = opcode >> 4;
+						let imm16 = read_imm16(cpu, mem);
 						set_r16(cpu, r16, imm16);
 						(3, 3)
 					} else {
@@ -361,6 +497,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 				6 => {
 					// 00---110
 					let r8 = opcode >> 3;
+					let imm8 = read_imm8(cpu, mem);
 					let r8dst_cost = set_r8(cpu, mem, r8, imm8);
 					(2, 2 + r8dst_cost)
 				}
@@ -460,7 +597,12 @@ pub fn cycle(gb: &mut GB) -> u64 {
 			// Block 1
 			//
 			if opcode == 0x76 {
-				cpu.halt = true;
+				if !cpu.ime && pending != 0 {
+					// HALT exits immediately, but PC doesn't increment for the next fetch
+					cpu.halt_bug = true;
+				} else {
+					cpu.halt = true;
+				}
 				(1, 1)
 			} else {
 				let r8_src = opcode & 0b111;
@@ -594,6 +736,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 			//
 			match opcode & 0b00111111 {
 				0b000110 => {
+					let imm8 = read_imm8(cpu, mem);
 					let value = cpu.a.overflowing_add(imm8);
 					cpu.f.z = value.0 == 0;
 					cpu.f.n = false;
@@ -603,6 +746,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(2, 2)
 				}
 				0b001110 => {
+					let imm8 = read_imm8(cpu, mem);
 					let carry_flag = if cpu.f.c { 1 } else { 0 };
 
 					let imm8_plus_carry = imm8.overflowing_add(carry_flag);
@@ -619,6 +763,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(2, 2)
 				}
 				0b010110 => {
+					let imm8 = read_imm8(cpu, mem);
 					let value = cpu.a.overflowing_sub(imm8);
 					cpu.f.z = value.0 == 0;
 					cpu.f.n = true;
@@ -628,6 +773,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(2, 2)
 				}
 				0b011110 => {
+					let imm8 = read_imm8(cpu, mem);
 					let carry = if cpu.f.c { 1 } else { 0 };
 					let imm8_plus_carry = imm8.overflowing_add(carry);
 
@@ -650,6 +796,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(2, 2)
 				}
 				0b100110 => {
+					let imm8 = read_imm8(cpu, mem);
 					cpu.a &= imm8;
 					cpu.f.z = cpu.a == 0;
 					cpu.f.n = false;
@@ -658,6 +805,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(2, 2)
 				}
 				0b101110 => {
+					let imm8 = read_imm8(cpu, mem);
 					cpu.a ^= imm8;
 					cpu.f.z = cpu.a == 0;
 					cpu.f.n = false;
@@ -666,6 +814,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(2, 2)
 				}
 				0b110110 => {
+					let imm8 = read_imm8(cpu, mem);
 					cpu.a |= imm8;
 					cpu.f.z = cpu.a == 0;
 					cpu.f.n = false;
@@ -674,6 +823,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(2, 2)
 				}
 				0b111110 => {
+					let imm8 = read_imm8(cpu, mem);
 					// calculate by subtracting (a - imm8)
 
 					// if zero, then they were equal
@@ -688,19 +838,17 @@ pub fn cycle(gb: &mut GB) -> u64 {
 				}
 
 				0b001001 => {
-					cpu.pc = mem.peek16(cpu.sp);
-					cpu.sp += 2;
+					cpu.pc = pop16(cpu, mem);
 					(0, 4)
 				}
 				0b011001 => {
 					cpu.ime = true;
 					cpu.ime_soon = false;
-					cpu.pc = mem.peek16(cpu.sp);
-					cpu.sp += 2;
+					cpu.pc = pop16(cpu, mem);
 					(0, 4)
 				}
 				0b000011 => {
-					cpu.pc = imm16;
+					cpu.pc = read_imm16(cpu, mem);
 					(0, 4)
 				}
 				0b101001 => {
@@ -708,15 +856,15 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(0, 1)
 				}
 				0b001101 => {
-					cpu.sp -= 2;
-					mem.poke16(cpu.sp, cpu.pc + 3);
-					cpu.pc = imm16;
+					let addr = read_imm16(cpu, mem);
+					push16(cpu, mem, cpu.pc.wrapping_add(3));
+					cpu.pc = addr;
 					(0, 6)
 				}
 				0b000000 | 0b001000 | 0b010000 | 0b011000 => {
+					mem.tick(); // condition check
 					if get_cond(&cpu.f, (opcode >> 3) & 0b11) {
-						cpu.pc = mem.peek16(cpu.sp);
-						cpu.sp += 2;
+						cpu.pc = pop16(cpu, mem);
 						(0, 5)
 					} else {
 						(1, 2)
@@ -724,18 +872,19 @@ pub fn cycle(gb: &mut GB) -> u64 {
 				}
 				0b000010 | 0b001010 | 0b010010 | 0b011010 => {
 					let cond = (opcode >> 3) & 0b11;
+					let addr = read_imm16(cpu, mem);
 					if get_cond(&cpu.f, cond) {
-						cpu.pc = imm16;
+						cpu.pc = addr;
 						(0, 4)
 					} else {
 						(3, 3)
 					}
 				}
 				0b000100 | 0b001100 | 0b010100 | 0b011100 => {
+					let addr = read_imm16(cpu, mem);
 					if get_cond(&cpu.f, (opcode >> 3) & 0b11) {
-						cpu.sp -= 2;
-						mem.poke16(cpu.sp, cpu.pc + 3);
-						cpu.pc = imm16;
+						push16(cpu, mem, cpu.pc.wrapping_add(3));
+						cpu.pc = addr;
 						(0, 6)
 					} else {
 						(3, 3)
@@ -743,17 +892,18 @@ pub fn cycle(gb: &mut GB) -> u64 {
 				}
 				0b000001 | 0b010001 | 0b100001 | 0b110001 => {
 					let r16 = (opcode >> 4) & 0b11;
-					set_r16stk(cpu, r16, mem.peek16(cpu.sp));
-					cpu.sp += 2;
+					let value = pop16(cpu, mem);
+					set_r16stk(cpu, r16, value);
 					(1, 3)
 				}
 				0b000101 | 0b010101 | 0b100101 | 0b110101 => {
 					let r16 = (opcode >> 4) & 0b11;
-					cpu.sp -= 2;
-					mem.poke16(cpu.sp, get_r16stk(cpu, r16));
+					let value = get_r16stk(cpu, r16);
+					push16(cpu, mem, value);
 					(1, 4)
 				}
 				0b001011 => {
+					let imm8 = read_imm8(cpu, mem);
 					let b3_id = (imm8 >> 3) & 0b111;
 					let r8_id = imm8 & 0b111;
 					match imm8 >> 6 {
@@ -876,32 +1026,36 @@ pub fn cycle(gb: &mut GB) -> u64 {
 				}
 
 				0b100010 => {
-					mem.poke(0xFF00 | (cpu.c as u16), cpu.a);
+					mem.write(0xFF00 | (cpu.c as u16), cpu.a);
 					(1, 2)
 				}
 				0b100000 => {
-					mem.poke(0xFF00 | (imm8 as u16), cpu.a);
+					let imm8 = read_imm8(cpu, mem);
+					mem.write(0xFF00 | (imm8 as u16), cpu.a);
 					(2, 3)
 				}
 				0b101010 => {
-					mem.poke(imm16, cpu.a);
+					let addr = read_imm16(cpu, mem);
+					mem.write(addr, cpu.a);
 					(3, 4)
 				}
 				0b110010 => {
-					cpu.a = mem.peek(0xff00 | (cpu.c as u16));
+					cpu.a = mem.read(0xff00 | (cpu.c as u16));
 					(1, 2)
 				}
 				0b110000 => {
-					cpu.a = mem.peek(0xFF00 | (imm8 as u16));
+					let imm8 = read_imm8(cpu, mem);
+					cpu.a = mem.read(0xFF00 | (imm8 as u16));
 					(2, 3)
 				}
 				0b111010 => {
-					let value = mem.peek(imm16);
+					let addr = read_imm16(cpu, mem);
+					let value = mem.read(addr);
 					cpu.a = value;
 					(3, 4)
 				}
 				0b101000 => {
-					let uadd = u8_as_signed_ofs(imm8);
+					let uadd = u8_as_signed_ofs(read_imm8(cpu, mem));
 					let old_sp = cpu.sp;
 					cpu.sp = old_sp.wrapping_add(uadd);
 					cpu.f.z = false;
@@ -911,7 +1065,7 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(2, 4)
 				}
 				0b111000 => {
-					let uadd = u8_as_signed_ofs(imm8);
+					let uadd = u8_as_signed_ofs(read_imm8(cpu, mem));
 					cpu.set_hl(cpu.sp.wrapping_add(uadd));
 					cpu.f.z = false;
 					cpu.f.n = false;
@@ -933,14 +1087,9 @@ pub fn cycle(gb: &mut GB) -> u64 {
 					(1, 1)
 				}
 
-				// TODO: invalid opcodes:
-				// $D3, $DB, $DD, $E3, $E4, $EB, $EC, $ED, $F4, $FC, and $FD
-
-				//
 				_ => {
 					assert!(opcode & 0b11000111 == 0b11000111);
-					cpu.sp -= 2;
-					mem.poke16(cpu.sp, cpu.pc + 1);
+					push16(cpu, mem, cpu.pc.wrapping_add(1));
 					cpu.pc = (opcode as u16) & 0b111000;
 					(0, 4)
 				}
@@ -956,5 +1105,27 @@ pub fn cycle(gb: &mut GB) -> u64 {
 	}
 
 	cpu.pc = cpu.pc.wrapping_add(bytes);
-	mcycles
+
+	match opcode {
+		// CALL and RST, when the return address was pushed
+		0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC | 0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7
+		| 0xFF
+			if cpu.sp == sp_before.wrapping_sub(2) =>
+		{
+			cpu.calls.call(Frame {
+				call_site: (mem.bank_at(call_site), call_site),
+				target: (mem.bank_at(cpu.pc), cpu.pc),
+				sp: cpu.sp,
+				interrupt: false,
+			})
+		}
+		0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9 => cpu.calls.ret(cpu.sp),
+		_ => {}
+	}
+
+	// Internal M-cycles that didn't access memory
+	while mem.mcycles - start < mcycles {
+		mem.tick();
+	}
+	mem.mcycles - start
 }
@@ -0,0 +1,515 @@
+use crate::GB;
+use crate::cpu::{self, CPU};
+use crate::disasm;
+use crate::iolog::IoLog;
+use crate::symbols::Symbols;
+use std::sync::mpsc::{Receiver, channel};
+
+// Breakpoints, watchpoints and stepping on top of cpu::cycle.
+// The frontend calls Debugger::cycle instead of cpu::cycle, and feeds it
+// commands from the terminal REPL or from the UI between steps.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg {
+	A,
+	F,
+	B,
+	C,
+	D,
+	E,
+	H,
+	L,
+	AF,
+	BC,
+	DE,
+	HL,
+	SP,
+	PC,
+}
+impl Reg {
+	pub fn from_name(name: &str) -> Option<Reg> {
+		Some(match name.to_ascii_lowercase().as_str() {
+			"a" => Reg::A,
+			"f" => Reg::F,
+			"b" => Reg::B,
+			"c" => Reg::C,
+			"d" => Reg::D,
+			"e" => Reg::E,
+			"h" => Reg::H,
+			"l" => Reg::L,
+			"af" => Reg::AF,
+			"bc" => Reg::BC,
+			"de" => Reg::DE,
+			"hl" => Reg::HL,
+			"sp" => Reg::SP,
+			"pc" => Reg::PC,
+			_ => return None,
+		})
+	}
+	pub fn get(&self, cpu: &CPU) -> u16 {
+		match self {
+			Reg::A => cpu.a as u16,
+			Reg::F => cpu.f.bits() as u16,
+			Reg::B => cpu.b as u16,
+			Reg::C => cpu.c as u16,
+			Reg::D => cpu.d as u16,
+			Reg::E => cpu.e as u16,
+			Reg::H => cpu.h as u16,
+			Reg::L => cpu.l as u16,
+			Reg::AF => cpu.get_af(),
+			Reg::BC => cpu.get_bc(),
+			Reg::DE => cpu.get_de(),
+			Reg::HL => cpu.get_hl(),
+			Reg::SP => cpu.sp,
+			Reg::PC => cpu.pc,
+		}
+	}
+}
+
+pub struct Breakpoint {
+	pub addr: u16,
+	pub bank: Option<usize>,           // only break when this bank is mapped
+	pub condition: Option<(Reg, u16)>, // only break when the register has this value
+}
+impl Breakpoint {
+	fn hit(&self, gb: &GB) -> bool {
+		if gb.cpu.pc != self.addr {
+			return false;
+		}
+		if let Some(bank) = self.bank
+			&& gb.bus.bank_at(self.addr) != bank
+		{
+			return false;
+		}
+		match self.condition {
+			Some((reg, value)) => reg.get(&gb.cpu) == value,
+			None => true,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+	Read,
+	Write,
+	Access,
+}
+
+pub struct Watchpoint {
+	pub start: u16,
+	pub end: u16, // inclusive
+	pub kind: WatchKind,
+}
+impl Watchpoint {
+	pub fn matches(&self, addr: u16, write: bool) -> bool {
+		let kind_matches = match self.kind {
+			WatchKind::Read => !write,
+			WatchKind::Write => write,
+			WatchKind::Access => true,
+		};
+		kind_matches && (self.start..=self.end).contains(&addr)
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+	pub addr: u16,
+	pub data: u8,
+	pub write: bool,
+	pub kind: WatchKind, // of the watchpoint that matched
+}
+
+enum Step {
+	Into,
+	Over { ret: u16, sp: u16 },
+	Out { sp: u16 },
+	Frame,
+}
+
+#[derive(Debug)]
+pub enum Stop {
+	Pause,
+	Breakpoint(usize),
+	Watchpoint(WatchHit),
+	Interrupt(u16),
+	Locked(u16),
+	Step,
+	Frame,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+	pub breakpoints: Vec<Breakpoint>,
+	pub break_on_interrupt: bool,
+	pub paused: bool,
+	step: Option<Step>,
+	repl: Option<Receiver<String>>,
+}
+impl Debugger {
+	pub fn pause(&mut self) {
+		self.paused = true;
+		self.step = None;
+	}
+	pub fn resume(&mut self) {
+		self.paused = false;
+		self.step = None;
+	}
+	pub fn step_into(&mut self) {
+		self.paused = false;
+		self.step = Some(Step::Into);
+	}
+	/// Like step_into, but runs calls until they return
+	pub fn step_over(&mut self, gb: &GB) {
+		let len = match gb.bus.peek_unlogged(gb.cpu.pc) {
+			// CALL, CALL cc
+			0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => 3,
+			// RST
+			op if op & 0b11_000_111 == 0b11_000_111 => 1,
+			_ => return self.step_into(),
+		};
+		self.paused = false;
+		self.step = Some(Step::Over {
+			ret: gb.cpu.pc.wrapping_add(len),
+			sp: gb.cpu.sp,
+		});
+	}
+	/// Run until the current function returns
+	pub fn step_out(&mut self, gb: &GB) {
+		self.paused = false;
+		self.step = Some(Step::Out { sp: gb.cpu.sp });
+	}
+	pub fn run_to_frame(&mut self) {
+		self.paused = false;
+		self.step = Some(Step::Frame);
+	}
+
+	/// Run one CPU step unless paused.
+	/// Returns the M-cycles that passed, and why execution stopped if it did.
+	pub fn cycle(&mut self, gb: &mut GB) -> (u64, Option<Stop>) {
+		if self.paused {
+			return (0, None);
+		}
+		let was_locked = gb.cpu.locked.is_some();
+		let pc = gb.cpu.pc;
+		let opcode = gb.bus.peek_unlogged(pc);
+
+		let mcycles = cpu::cycle(gb);
+
+		let stop = self.check(gb, opcode, pc, was_locked);
+		if stop.is_some() {
+			self.paused = true;
+			self.step = None;
+		}
+		(mcycles, stop)
+	}
+
+	fn check(&mut self, gb: &mut GB, opcode: u8, pc: u16, was_locked: bool) -> Option<Stop> {
+		if let Some(pc) = gb.cpu.locked
+			&& !was_locked
+		{
+			return Some(Stop::Locked(pc));
+		}
+		if let Some(hit) = gb.bus.watch_hit.take() {
+			return Some(Stop::Watchpoint(hit));
+		}
+		if let Some(vector) = gb.cpu.dispatched
+			&& self.break_on_interrupt
+		{
+			return Some(Stop::Interrupt(vector));
+		}
+		// PC stays put while the CPU is halted, stopped or locked up, and a
+		// breakpoint there would fire on every cycle and never let go
+		let waiting = gb.cpu.halt || gb.cpu.stopped || gb.cpu.locked.is_some();
+		if (gb.cpu.pc != pc || !waiting)
+			&& let Some(i) = self.breakpoints.iter().position(|b| b.hit(gb))
+		{
+			return Some(Stop::Breakpoint(i));
+		}
+		let done = match self.step {
+			None => false,
+			Some(Step::Into) => true,
+			Some(Step::Over { ret, sp }) => gb.cpu.pc == ret && gb.cpu.sp >= sp,
+			Some(Step::Out { sp }) => is_return(opcode) && gb.cpu.sp > sp,
+			Some(Step::Frame) => gb.bus.frame_done,
+		};
+		match (done, &self.step) {
+			(true, Some(Step::Frame)) => Some(Stop::Frame),
+			(true, _) => Some(Stop::Step),
+			(false, _) => None,
+		}
+	}
+
+	/// Why execution stopped, followed by the next instruction
+	pub fn describe(&self, stop: &Stop, gb: &GB) -> String {
+		let pc = gb.cpu.pc;
+		let reason = match stop {
+			Stop::Pause => format!("Paused at {pc:04x}"),
+			Stop::Breakpoint(i) => format!("Breakpoint {i} at {}", location(gb, pc)),
+			Stop::Watchpoint(hit) => format!(
+				"Watchpoint: {} {:04x} = {:02x}, stopped at {pc:04x}",
+				if hit.write { "write" } else { "read" },
+				hit.addr,
+				hit.data
+			),
+			Stop::Interrupt(vector) => format!("Interrupt entered at {vector:04x}"),
+			Stop::Locked(at) => format!("CPU locked up by illegal opcode at {at:04x}"),
+			Stop::Step => return disassemble(gb, pc, 1),
+			Stop::Frame => format!("Frame done at {pc:04x}"),
+		};
+		format!("{reason}\n{}", disassemble(gb, pc, 1))
+	}
+
+	/// Read debugger commands from stdin on a separate thread
+	pub fn start_repl(&mut self) {
+		let (tx, rx) = channel();
+		std::thread::spawn(move || {
+			for line in std::io::stdin().lines() {
+				let Ok(line) = line else { break };
+				if tx.send(line).is_err() {
+					break;
+				}
+			}
+		});
+		self.repl = Some(rx);
+	}
+	/// Run any commands typed into the REPL since the last call
+	pub fn poll_repl(&mut self, gb: &mut GB) {
+		let lines: Vec<String> = match &self.repl {
+			Some(rx) => rx.try_iter().collect(),
+			None => return,
+		};
+		for line in lines {
+			match self.command(gb, &line) {
+				Ok(out) if out.is_empty() => {}
+				Ok(out) => println!("{out}"),
+				Err(e) => println!("{e}"),
+			}
+		}
+	}
+
+	pub fn command(&mut self, gb: &mut GB, line: &str) -> Result<String, String> {
+		let words: Vec<&str> = line.split_whitespace().collect();
+		let Some(&cmd) = words.first() else {
+			return Ok(String::new());
+		};
+		let args = &words[1..];
+		match cmd {
+			"c" | "continue" => self.resume(),
+			"p" | "pause" => {
+				self.pause();
+				return Ok(self.describe(&Stop::Pause, gb));
+			}
+			"s" | "step" => self.step_into(),
+			"n" | "next" => self.step_over(gb),
+			"f" | "finish" => self.step_out(gb),
+			"frame" => self.run_to_frame(),
+			"b" | "break" => {
+				let bp = parse_breakpoint(args, &gb.bus.symbols)?;
+				self.breakpoints.push(bp);
+				return Ok(format!("Breakpoint {}", self.breakpoints.len() - 1));
+			}
+			"w" | "watch" => {
+				let wp = parse_watchpoint(args)?;
+				gb.bus.watchpoints.push(wp);
+				return Ok(format!("Watchpoint {}", gb.bus.watchpoints.len() - 1));
+			}
+			"d" | "delete" => {
+				let i = parse_index(args, self.breakpoints.len())?;
+				self.breakpoints.remove(i);
+			}
+			"dw" => {
+				let i = parse_index(args, gb.bus.watchpoints.len())?;
+				gb.bus.watchpoints.remove(i);
+			}
+			"int" => {
+				self.break_on_interrupt = !self.break_on_interrupt;
+				return Ok(format!("Break on interrupt: {}", self.break_on_interrupt));
+			}
+			"l" | "list" => return Ok(self.list(gb)),
+			"r" | "regs" => return Ok(format!("{:x?}", gb.cpu)),
+			"io" if args.first() == Some(&"off") => {
+				if let Some(log) = gb.bus.io_log.take() {
+					log.into_inner().flush().map_err(|e| e.to_string())?;
+				}
+				return Ok("IO log stopped".into());
+			}
+			"io" => {
+				let count = match args.first() {
+					Some(count) => count.parse().map_err(|_| format!("Bad count {count}"))?,
+					None => 20,
+				};
+				let log = gb.bus.io_log.get_or_insert_with(|| IoLog::default().into());
+				return Ok(log.borrow().recent(count).join("\n"));
+			}
+			"bt" | "backtrace" => return Ok(gb.cpu.calls.report(&gb.bus, gb.cpu.pc)),
+			"dis" => {
+				let addr = match args.first() {
+					Some(addr) => parse_u16(addr)?,
+					None => gb.cpu.pc,
+				};
+				let count = match args.get(1) {
+					Some(count) => parse_u16(count)?,
+					None => 10,
+				};
+				return Ok(disassemble(gb, addr, count));
+			}
+			"x" => {
+				let addr = parse_u16(args.first().ok_or("x <addr> [len]")?)?;
+				let len = match args.get(1) {
+					Some(len) => parse_u16(len)?,
+					None => 16,
+				};
+				return Ok(hexdump(gb, addr, len));
+			}
+			"set" => {
+				let [addr, data] = args else {
+					return Err("set <addr> <byte>".into());
+				};
+				gb.bus.poke(parse_u16(addr)?, parse_u16(data)? as u8);
+			}
+			"h" | "help" => return Ok(HELP.into()),
+			_ => return Err(format!("Unknown command {cmd}, try help")),
+		}
+		Ok(String::new())
+	}
+
+	fn list(&self, gb: &GB) -> String {
+		let mut out = vec![];
+		for (i, bp) in self.breakpoints.iter().enumerate() {
+			let bank = match bp.bank {
+				Some(bank) => format!("{bank:02x}:"),
+				None => String::new(),
+			};
+			let cond = match bp.condition {
+				Some((reg, value)) => format!(" if {reg:?}=={value:x}"),
+				None => String::new(),
+			};
+			out.push(format!("b{i}: {bank}{:04x}{cond}", bp.addr));
+		}
+		for (i, wp) in gb.bus.watchpoints.iter().enumerate() {
+			out.push(format!(
+				"w{i}: {:04x}-{:04x} {:?}",
+				wp.start, wp.end, wp.kind
+			));
+		}
+		out.join("\n")
+	}
+}
+
+const HELP: &str = "\
+c, continue                  resume execution
+p, pause                     stop execution
+s, step                      run one instruction
+n, next                      step over calls
+f, finish                    run until the current function returns
+frame                        run until the next frame is done
+b, break [bank:]addr|label [if reg==value]
+w, watch addr[-end] [r|w|rw] stop on reads and/or writes
+d, delete n / dw n           remove breakpoint / watchpoint n
+int                          toggle breaking on interrupt entry
+l, list                      list breakpoints and watchpoints
+r, regs                      show CPU registers
+bt, backtrace                show the call stack and recent instructions
+io [count|off]               show recent IO accesses, logging them from now on
+dis [addr] [count]           disassemble, from PC by default
+x addr [len]                 dump memory
+set addr byte                write memory
+Numbers are hex.";
+
+fn is_return(opcode: u8) -> bool {
+	matches!(opcode, 0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8)
+}
+
+fn parse_u16(s: &str) -> Result<u16, String> {
+	let digits = s.trim_start_matches('$').trim_start_matches("0x");
+	u16::from_str_radix(digits, 16).map_err(|_| format!("Bad number {s}"))
+}
+
+fn parse_index(args: &[&str], len: usize) -> Result<usize, String> {
+	let i: usize = args
+		.first()
+		.and_then(|x| x.parse().ok())
+		.ok_or("Expected an index")?;
+	if i >= len {
+		return Err(format!("No such index {i}"));
+	}
+	Ok(i)
+}
+
+fn parse_breakpoint(args: &[&str], symbols: &Symbols) -> Result<Breakpoint, String> {
+	let location = args
+		.first()
+		.ok_or("break [bank:]addr|label [if reg==value]")?;
+	let (bank, addr) = match (symbols.lookup(location), location.split_once(':')) {
+		(Some((bank, addr)), _) => (Some(bank), addr),
+		(None, Some((bank, addr))) => (Some(parse_u16(bank)? as usize), parse_u16(addr)?),
+		(None, None) => (None, parse_u16(location)?),
+	};
+	let condition = match args[1..] {
+		[] => None,
+		["if", cond] => {
+			let (reg, value) = cond
+				.split_once("==")
+				.ok_or("Condition must be reg==value")?;
+			let reg = Reg::from_name(reg).ok_or(format!("Unknown register {reg}"))?;
+			Some((reg, parse_u16(value)?))
+		}
+		_ => return Err("break [bank:]addr|label [if reg==value]".into()),
+	};
+	Ok(Breakpoint {
+		addr,
+		bank,
+		condition,
+	})
+}
+
+fn parse_watchpoint(args: &[&str]) -> Result<Watchpoint, String> {
+	let range = args.first().ok_or("watch addr[-end] [r|w|rw]")?;
+	let (start, end) = match range.split_once('-') {
+		Some((start, end)) => (parse_u16(start)?, parse_u16(end)?),
+		None => (parse_u16(range)?, parse_u16(range)?),
+	};
+	let kind = match args.get(1).copied() {
+		Some("r") => WatchKind::Read,
+		Some("w") | None => WatchKind::Write,
+		Some("rw") => WatchKind::Access,
+		Some(x) => return Err(format!("Unknown watch kind {x}")),
+	};
+	Ok(Watchpoint { start, end, kind })
+}
+
+/// An address with the label it falls under, if any
+pub fn location(gb: &GB, addr: u16) -> String {
+	match gb.bus.symbols.describe(gb.bus.bank_at(addr), addr) {
+		Some(label) => format!("{addr:04x} ({label})"),
+		None => format!("{addr:04x}"),
+	}
+}
+
+fn disassemble(gb: &GB, mut addr: u16, count: u16) -> String {
+	let mut out = vec![];
+	for _ in 0..count {
+		let bank = gb.bus.bank_at(addr);
+		if let Some(label) = gb.bus.symbols.label(bank, addr) {
+			out.push(format!("{label}:"));
+		}
+		let instr = disasm::at(&gb.bus, addr);
+		out.push(disasm::format_line(bank, addr, &instr, |a| {
+			gb.bus.peek_unlogged(a)
+		}));
+		addr = addr.wrapping_add(instr.len);
+	}
+	out.join("\n")
+}
+
+fn hexdump(gb: &GB, addr: u16, len: u16) -> String {
+	let mut out = vec![];
+	for row in (0..len).step_by(16) {
+		let row_addr = addr.wrapping_add(row);
+		let bytes: Vec<String> = (0..16.min(len - row))
+			.map(|i| format!("{:02x}", gb.bus.peek(row_addr.wrapping_add(i))))
+			.collect();
+		out.push(format!("{row_addr:04x}: {}", bytes.join(" ")));
+	}
+	out.join("\n")
+}
@@ -0,0 +1,226 @@
+use crate::bus::Bus;
+use crate::cart::ROM_BANK_SIZE;
+use crate::cdl::Cdl;
+use crate::symbols::Symbols;
+
+// SM83 disassembler producing RGBDS syntax
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEM: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const COND: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = [
+	"add a,", "adc a,", "sub a,", "sbc a,", "and a,", "xor a,", "or a,", "cp a,",
+];
+const BLOCK0_MISC: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const CB_SHIFTS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+pub struct Instruction {
+	pub len: u16,
+	pub text: String,
+	pub target: Option<u16>, // address operand, for jumps, calls and pointers
+}
+impl Instruction {
+	/// Show the address operand as a label, given the bank it points into
+	pub fn label_target(&mut self, symbols: &Symbols, bank: usize) {
+		if let Some(target) = self.target
+			&& let Some(label) = symbols.describe(bank, target)
+		{
+			self.text = self.text.replace(&format!("${target:04x}"), &label);
+		}
+	}
+}
+
+/// Disassemble the instruction at addr as the CPU currently sees it
+pub fn at(bus: &Bus, addr: u16) -> Instruction {
+	let mut instr = decode(addr, |a| bus.peek_unlogged(a));
+	if let Some(target) = instr.target {
+		instr.label_target(&bus.symbols, bus.bank_at(target));
+	}
+	instr
+}
+
+/// Decode one instruction. fetch reads the byte at an address.
+pub fn decode(addr: u16, fetch: impl Fn(u16) -> u8) -> Instruction {
+	let opcode = fetch(addr);
+	let n8 = fetch(addr.wrapping_add(1));
+	let n16 = u16::from_le_bytes([n8, fetch(addr.wrapping_add(2))]);
+	// relative jump target
+	let e8 = addr.wrapping_add(2).wrapping_add(n8 as i8 as u16);
+
+	let x = (opcode >> 3) & 0b111;
+	let z = opcode & 0b111;
+	let p = (x >> 1) as usize;
+
+	let target = match opcode {
+		0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Some(e8),
+		0x01 | 0x11 | 0x21 | 0x31 | 0x08 | 0xEA | 0xFA => Some(n16),
+		0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA | 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => Some(n16),
+		_ => None,
+	};
+
+	let (len, text) = match opcode {
+		// Block 0
+		0x00 => (1, "nop".into()),
+		0x08 => (3, format!("ld [${n16:04x}], sp")),
+		0x10 => (2, "stop".into()),
+		0x18 => (2, format!("jr ${e8:04x}")),
+		0x20 | 0x28 | 0x30 | 0x38 => (2, format!("jr {}, ${e8:04x}", COND[x as usize - 4])),
+		0x01..=0x3F => match z {
+			1 if x & 1 == 0 => (3, format!("ld {}, ${n16:04x}", R16[p])),
+			1 => (1, format!("add hl, {}", R16[p])),
+			2 if x & 1 == 0 => (1, format!("ld {}, a", R16_MEM[p])),
+			2 => (1, format!("ld a, {}", R16_MEM[p])),
+			3 if x & 1 == 0 => (1, format!("inc {}", R16[p])),
+			3 => (1, format!("dec {}", R16[p])),
+			4 => (1, format!("inc {}", R8[x as usize])),
+			5 => (1, format!("dec {}", R8[x as usize])),
+			6 => (2, format!("ld {}, ${n8:02x}", R8[x as usize])),
+			_ => (1, BLOCK0_MISC[x as usize].into()),
+		},
+
+		// Block 1
+		0x76 => (1, "halt".into()),
+		0x40..=0x7F => (1, format!("ld {}, {}", R8[x as usize], R8[z as usize])),
+
+		// Block 2
+		0x80..=0xBF => (1, format!("{} {}", ALU[x as usize], R8[z as usize])),
+
+		// Block 3
+		0xC0 | 0xC8 | 0xD0 | 0xD8 => (1, format!("ret {}", COND[x as usize])),
+		0xC9 => (1, "ret".into()),
+		0xD9 => (1, "reti".into()),
+		0xC2 | 0xCA | 0xD2 | 0xDA => (3, format!("jp {}, ${n16:04x}", COND[x as usize])),
+		0xC3 => (3, format!("jp ${n16:04x}")),
+		0xE9 => (1, "jp hl".into()),
+		0xC4 | 0xCC | 0xD4 | 0xDC => (3, format!("call {}, ${n16:04x}", COND[x as usize])),
+		0xCD => (3, format!("call ${n16:04x}")),
+		0xC1 | 0xD1 | 0xE1 | 0xF1 => (1, format!("pop {}", R16_STK[p])),
+		0xC5 | 0xD5 | 0xE5 | 0xF5 => (1, format!("push {}", R16_STK[p])),
+		0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {
+			(2, format!("{} ${n8:02x}", ALU[x as usize]))
+		}
+		0xCB => (2, decode_cb(n8)),
+		0xE0 => (2, format!("ldh [$ff{n8:02x}], a")),
+		0xE2 => (1, "ldh [c], a".into()),
+		0xEA => (3, format!("ld [${n16:04x}], a")),
+		0xF0 => (2, format!("ldh a, [$ff{n8:02x}]")),
+		0xF2 => (1, "ldh a, [c]".into()),
+		0xFA => (3, format!("ld a, [${n16:04x}]")),
+		0xE8 => (2, format!("add sp, {}", signed(n8))),
+		0xF8 => (2, format!("ld hl, sp{}", signed(n8))),
+		0xF9 => (1, "ld sp, hl".into()),
+		0xF3 => (1, "di".into()),
+		0xFB => (1, "ei".into()),
+		0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+			(1, format!("rst ${:02x}", opcode & 0b111000))
+		}
+		// Illegal opcodes
+		_ => (1, format!("db ${opcode:02x}")),
+	};
+	Instruction { len, text, target }
+}
+
+fn decode_cb(op: u8) -> String {
+	let bit = (op >> 3) & 0b111;
+	let r8 = R8[op as usize & 0b111];
+	match op >> 6 {
+		0 => format!("{} {r8}", CB_SHIFTS[bit as usize]),
+		1 => format!("bit {bit}, {r8}"),
+		2 => format!("res {bit}, {r8}"),
+		_ => format!("set {bit}, {r8}"),
+	}
+}
+
+fn signed(n8: u8) -> String {
+	match n8 as i8 {
+		n if n < 0 => format!("-${:02x}", n.unsigned_abs()),
+		n => format!("+${n:02x}"),
+	}
+}
+
+/// One line per instruction: address, raw bytes and mnemonic
+pub fn format_line(
+	bank: usize,
+	addr: u16,
+	instr: &Instruction,
+	fetch: impl Fn(u16) -> u8,
+) -> String {
+	let bytes: Vec<String> = (0..instr.len)
+		.map(|i| format!("{:02x}", fetch(addr.wrapping_add(i))))
+		.collect();
+	format!(
+		"{bank:02x}:{addr:04x}  {:<9} {}",
+		bytes.join(" "),
+		instr.text
+	)
+}
+
+/// Disassemble a range of a ROM bank, addressed as it appears to the CPU.
+/// Bytes the code/data log has only seen used as data are shown as db.
+pub fn dump_bank(
+	rom: &[[u8; ROM_BANK_SIZE]],
+	symbols: &Symbols,
+	cdl: Option<&Cdl>,
+	bank: usize,
+	start: u16,
+	end: u16,
+) -> Vec<String> {
+	let base: u16 = if bank == 0 { 0x0000 } else { 0x4000 };
+	let fetch = |a: u16| match a.wrapping_sub(base) as usize {
+		ofs if ofs < ROM_BANK_SIZE => rom[bank][ofs],
+		_ => 0xFF,
+	};
+	let mut lines = vec![];
+	let mut addr = start;
+	while addr <= end {
+		if let Some(label) = symbols.label(bank, addr) {
+			lines.push(format!("{label}:"));
+		}
+		let is_data = |a: u16| {
+			let ofs = bank * ROM_BANK_SIZE + a.wrapping_sub(base) as usize;
+			cdl.is_some_and(|cdl| cdl.is_data(ofs))
+		};
+		if is_data(addr) {
+			// up to 8 bytes per line, stopping at the next label
+			let mut bytes = vec![format!("${:02x}", fetch(addr))];
+			let mut next = addr.wrapping_add(1);
+			while bytes.len() < 8
+				&& next > addr
+				&& next <= end
+				&& is_data(next)
+				&& symbols.label(bank, next).is_none()
+			{
+				bytes.push(format!("${:02x}", fetch(next)));
+				next = next.wrapping_add(1);
+			}
+			lines.push(format!(
+				"{bank:02x}:{addr:04x}  {:<9} db {}",
+				"",
+				bytes.join(", ")
+			));
+			if next <= addr {
+				break;
+			}
+			addr = next;
+			continue;
+		}
+		let mut instr = decode(addr, fetch);
+		if let Some(target) = instr.target {
+			// Assume jumps into the switchable window stay in this bank
+			let target_bank = match target {
+				0x4000..=0x7FFF => bank,
+				0xD000..=0xDFFF => 1,
+				_ => 0,
+			};
+			instr.label_target(symbols, target_bank);
+		}
+		lines.push(format_line(bank, addr, &instr, fetch));
+		match addr.checked_add(instr.len) {
+			Some(next) => addr = next,
+			None => break,
+		}
+	}
+	lines
+}
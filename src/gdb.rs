@@ -0,0 +1,397 @@
+use crate::GB;
+use crate::cpu::Flags;
+use crate::debugger::{Breakpoint, Debugger, Stop, WatchKind, Watchpoint};
+use log::{info, warn};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// GDB remote serial protocol server.
+// GDB has no SM83 target, so the register layout is described in target.xml:
+// AF, BC, DE, HL, SP and PC, 16 bits each.
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<feature name="org.gnu.gdb.sm83.core">
+<reg name="af" bitsize="16" type="int"/>
+<reg name="bc" bitsize="16" type="int"/>
+<reg name="de" bitsize="16" type="int"/>
+<reg name="hl" bitsize="16" type="int"/>
+<reg name="sp" bitsize="16" type="data_ptr"/>
+<reg name="pc" bitsize="16" type="code_ptr"/>
+</feature>
+</target>
+"#;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+pub struct GdbStub {
+	listener: TcpListener,
+	stream: Option<TcpStream>, // None until GDB connects, and again after it leaves
+	input: Vec<u8>,
+	last_stop: String,
+	pub killed: bool, // GDB asked to end the session
+}
+impl GdbStub {
+	/// Listen on localhost. GDB connects during poll, so the window can open
+	/// while waiting for it.
+	pub fn listen(port: u16) -> std::io::Result<GdbStub> {
+		let listener = TcpListener::bind(("127.0.0.1", port))?;
+		listener.set_nonblocking(true)?;
+		info!(target: "tools", "Waiting for GDB on port {port}, paused until it connects");
+		Ok(GdbStub {
+			listener,
+			stream: None,
+			input: vec![],
+			last_stop: format!("S{SIGTRAP:02x}"),
+			killed: false,
+		})
+	}
+
+	/// Accept a connection if there's none, and handle the packets that
+	/// arrived since the last call. Only a failing listener is an error; a
+	/// client that goes away is dropped and the game runs on.
+	pub fn poll(&mut self, gb: &mut GB, debugger: &mut Debugger) -> std::io::Result<()> {
+		if self.stream.is_none() {
+			let (stream, addr) = match self.listener.accept() {
+				Ok(connection) => connection,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(e) => return Err(e),
+			};
+			if let Err(e) = stream
+				.set_nonblocking(true)
+				.and_then(|_| stream.set_nodelay(true))
+			{
+				warn!(target: "tools", "Dropped GDB connection from {addr}: {e}");
+				return Ok(());
+			}
+			info!(target: "tools", "GDB connected from {addr}");
+			self.stream = Some(stream);
+			self.input.clear();
+			// GDB expects the target to be stopped when it attaches
+			debugger.pause();
+			self.last_stop = format!("S{SIGTRAP:02x}");
+		}
+
+		if let Err(e) = self.serve(gb, debugger) {
+			self.connection_lost(e, debugger);
+		}
+		Ok(())
+	}
+
+	// Read what the client sent and answer the complete packets
+	fn serve(&mut self, gb: &mut GB, debugger: &mut Debugger) -> std::io::Result<()> {
+		let mut buf = [0; 4096];
+		while let Some(stream) = &mut self.stream {
+			match stream.read(&mut buf) {
+				Ok(0) => {
+					self.disconnect(debugger);
+					return Ok(());
+				}
+				Ok(n) => self.input.extend_from_slice(&buf[..n]),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) => return Err(e),
+			}
+		}
+
+		while let Some(packet) = self.next_packet()? {
+			let reply = self.handle(&packet, gb, debugger);
+			if let Some(reply) = reply {
+				self.send(&reply)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Tell GDB why execution stopped
+	pub fn report_stop(&mut self, stop: &Stop, debugger: &mut Debugger) {
+		self.last_stop = match stop {
+			Stop::Pause => format!("S{SIGINT:02x}"),
+			Stop::Watchpoint(hit) => {
+				let kind = match hit.kind {
+					WatchKind::Read => "rwatch",
+					WatchKind::Write => "watch",
+					WatchKind::Access => "awatch",
+				};
+				format!("T{SIGTRAP:02x}{kind}:{:x};", hit.addr)
+			}
+			_ => format!("S{SIGTRAP:02x}"),
+		};
+		let reply = self.last_stop.clone();
+		if let Err(e) = self.send(&reply) {
+			self.connection_lost(e, debugger);
+		}
+	}
+
+	fn connection_lost(&mut self, e: std::io::Error, debugger: &mut Debugger) {
+		warn!(target: "tools", "Lost the GDB connection: {e}");
+		self.disconnect(debugger);
+	}
+
+	// Let the game run on and wait for GDB to connect again
+	fn disconnect(&mut self, debugger: &mut Debugger) {
+		info!(target: "tools", "GDB disconnected");
+		self.stream = None;
+		debugger.resume();
+	}
+
+	// Pull the next "$data#cs" packet out of the input, acking it
+	fn next_packet(&mut self) -> std::io::Result<Option<String>> {
+		loop {
+			match self.input.first() {
+				None => return Ok(None),
+				// interrupt request, handled as a packet of its own
+				Some(0x03) => {
+					self.input.remove(0);
+					return Ok(Some("\x03".into()));
+				}
+				Some(b'$') => break,
+				// acks and noise
+				Some(_) => {
+					self.input.remove(0);
+				}
+			}
+		}
+		let Some(end) = self.input.iter().position(|&b| b == b'#') else {
+			return Ok(None);
+		};
+		if self.input.len() < end + 3 {
+			return Ok(None);
+		}
+		let data: Vec<u8> = self.input[1..end].to_vec();
+		let checksum = std::str::from_utf8(&self.input[end + 1..end + 3])
+			.ok()
+			.and_then(|cs| u8::from_str_radix(cs, 16).ok());
+		self.input.drain(..end + 3);
+
+		let Some(stream) = &mut self.stream else {
+			return Ok(None);
+		};
+		if checksum != Some(checksum_of(&data)) {
+			stream.write_all(b"-")?;
+			return Ok(None);
+		}
+		stream.write_all(b"+")?;
+		Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+	}
+
+	fn send(&mut self, data: &str) -> std::io::Result<()> {
+		let Some(stream) = &mut self.stream else {
+			return Ok(());
+		};
+		let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+		// the socket is non-blocking, but replies are small
+		stream.set_nonblocking(false)?;
+		let result = stream.write_all(packet.as_bytes());
+		stream.set_nonblocking(true)?;
+		result
+	}
+
+	// Returns the reply, or None when the reply comes later as a stop
+	fn handle(&mut self, packet: &str, gb: &mut GB, debugger: &mut Debugger) -> Option<String> {
+		let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+		Some(match cmd {
+			"\x03" => {
+				debugger.pause();
+				format!("S{SIGINT:02x}")
+			}
+			"?" => self.last_stop.clone(),
+			"g" => [
+				gb.cpu.get_af(),
+				gb.cpu.get_bc(),
+				gb.cpu.get_de(),
+				gb.cpu.get_hl(),
+				gb.cpu.sp,
+				gb.cpu.pc,
+			]
+			.iter()
+			.map(|r| hex_u16(*r))
+			.collect(),
+			"G" => {
+				for (i, chunk) in args.as_bytes().chunks(4).enumerate() {
+					let Some(value) = parse_le_u16(chunk) else {
+						return Some("E01".into());
+					};
+					set_register(gb, i, value);
+				}
+				"OK".into()
+			}
+			"p" => match usize::from_str_radix(args, 16) {
+				Ok(i) if i < 6 => hex_u16(get_register(gb, i)),
+				_ => "E01".into(),
+			},
+			"P" => {
+				let Some((i, value)) = args.split_once('=') else {
+					return Some("E01".into());
+				};
+				match (usize::from_str_radix(i, 16), parse_le_u16(value.as_bytes())) {
+					(Ok(i), Some(value)) if i < 6 => {
+						set_register(gb, i, value);
+						"OK".into()
+					}
+					_ => "E01".into(),
+				}
+			}
+			"m" => {
+				let Some((addr, len)) = parse_addr_len(args) else {
+					return Some("E01".into());
+				};
+				(0..len)
+					.map(|i| format!("{:02x}", gb.bus.peek(addr.wrapping_add(i))))
+					.collect()
+			}
+			"M" => {
+				let Some((range, data)) = args.split_once(':') else {
+					return Some("E01".into());
+				};
+				let Some((addr, len)) = parse_addr_len(range) else {
+					return Some("E01".into());
+				};
+				for i in 0..len {
+					let byte = data.get(i as usize * 2..i as usize * 2 + 2);
+					let Some(byte) = byte.and_then(|b| u8::from_str_radix(b, 16).ok()) else {
+						return Some("E01".into());
+					};
+					gb.bus.poke(addr.wrapping_add(i), byte);
+				}
+				"OK".into()
+			}
+			"c" => {
+				debugger.resume();
+				return None;
+			}
+			"s" => {
+				debugger.step_into();
+				return None;
+			}
+			"Z" | "z" => {
+				let insert = cmd == "Z";
+				let Some((kind, rest)) = args.split_once(',') else {
+					return Some("E01".into());
+				};
+				let Some((addr, len)) = parse_addr_len(rest) else {
+					return Some("E01".into());
+				};
+				match kind {
+					"0" | "1" => set_breakpoint(debugger, addr, insert),
+					"2" => set_watchpoint(gb, addr, len, WatchKind::Write, insert),
+					"3" => set_watchpoint(gb, addr, len, WatchKind::Read, insert),
+					"4" => set_watchpoint(gb, addr, len, WatchKind::Access, insert),
+					_ => return Some(String::new()),
+				}
+				"OK".into()
+			}
+			"D" => {
+				// the reply has to go out before the connection is dropped,
+				// and a failure doesn't matter when leaving anyway
+				self.send("OK").ok();
+				self.disconnect(debugger);
+				return None;
+			}
+			"k" => {
+				info!(target: "tools", "GDB ended the session");
+				self.stream = None;
+				self.killed = true;
+				return None;
+			}
+			"H" => "OK".into(),
+			"q" if args.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+".into(),
+			"q" if args == "Attached" => "1".into(),
+			"q" if args.starts_with("Xfer:features:read:target.xml:") => {
+				let range = &args["Xfer:features:read:target.xml:".len()..];
+				let Some((ofs, len)) = parse_addr_len(range) else {
+					return Some("E01".into());
+				};
+				let (ofs, len) = (ofs as usize, len as usize);
+				let chunk = TARGET_XML.get(ofs..TARGET_XML.len().min(ofs + len));
+				match chunk {
+					Some(chunk) if ofs + len < TARGET_XML.len() => format!("m{chunk}"),
+					Some(chunk) => format!("l{chunk}"),
+					None => "l".into(),
+				}
+			}
+			// unsupported
+			_ => String::new(),
+		})
+	}
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+	data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+// GDB sends register values in target byte order
+fn hex_u16(value: u16) -> String {
+	let [lo, hi] = value.to_le_bytes();
+	format!("{lo:02x}{hi:02x}")
+}
+
+fn parse_le_u16(hex: &[u8]) -> Option<u16> {
+	let hex = std::str::from_utf8(hex).ok()?;
+	let lo = u8::from_str_radix(hex.get(0..2)?, 16).ok()?;
+	let hi = u8::from_str_radix(hex.get(2..4)?, 16).ok()?;
+	Some(u16::from_le_bytes([lo, hi]))
+}
+
+fn parse_addr_len(args: &str) -> Option<(u16, u16)> {
+	let (addr, len) = args.split_once(',')?;
+	Some((
+		u16::from_str_radix(addr, 16).ok()?,
+		u16::from_str_radix(len, 16).ok()?,
+	))
+}
+
+fn get_register(gb: &GB, i: usize) -> u16 {
+	match i {
+		0 => gb.cpu.get_af(),
+		1 => gb.cpu.get_bc(),
+		2 => gb.cpu.get_de(),
+		3 => gb.cpu.get_hl(),
+		4 => gb.cpu.sp,
+		_ => gb.cpu.pc,
+	}
+}
+
+fn set_register(gb: &mut GB, i: usize, value: u16) {
+	let [lo, hi] = value.to_le_bytes();
+	let cpu = &mut gb.cpu;
+	match i {
+		0 => (cpu.a, cpu.f) = (hi, Flags::from_bits(lo)),
+		1 => (cpu.b, cpu.c) = (hi, lo),
+		2 => (cpu.d, cpu.e) = (hi, lo),
+		3 => (cpu.h, cpu.l) = (hi, lo),
+		4 => cpu.sp = value,
+		_ => cpu.pc = value,
+	}
+}
+
+fn set_breakpoint(debugger: &mut Debugger, addr: u16, insert: bool) {
+	if insert {
+		debugger.breakpoints.push(Breakpoint {
+			addr,
+			bank: None,
+			condition: None,
+		});
+	} else if let Some(i) = debugger.breakpoints.iter().position(|b| b.addr == addr) {
+		debugger.breakpoints.remove(i);
+	}
+}
+
+fn set_watchpoint(gb: &mut GB, addr: u16, len: u16, kind: WatchKind, insert: bool) {
+	let end = addr.wrapping_add(len.max(1) - 1);
+	if insert {
+		gb.bus.watchpoints.push(Watchpoint {
+			start: addr,
+			end,
+			kind,
+		});
+	} else if let Some(i) = gb
+		.bus
+		.watchpoints
+		.iter()
+		.position(|w| w.start == addr && w.end == end && w.kind == kind)
+	{
+		gb.bus.watchpoints.remove(i);
+	}
+}
@@ -0,0 +1,132 @@
+use crate::ioreg::name_of;
+use log::warn;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+
+// Log of CPU memory accesses, by default to the IO registers. Events are kept
+// in a ring buffer for the debugger and can also be written to a file:
+// frame 12 ly 144 dot 004  W FF40 LCDC  = 91
+
+const RING_LEN: usize = 256;
+
+pub struct Event {
+	pub frame: u64,
+	pub ly: u8,
+	pub dot: u64,
+	pub addr: u16,
+	pub data: u8,
+	pub write: bool,
+}
+impl std::fmt::Display for Event {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let kind = if self.write { 'W' } else { 'R' };
+		let name = match self.addr {
+			0xFF00..=0xFF7F | 0xFFFF => name_of(self.addr as usize),
+			_ => "",
+		};
+		write!(
+			f,
+			"frame {} ly {:03} dot {:03}  {kind} {:04X} {name:<5} = {:02X}",
+			self.frame, self.ly, self.dot, self.addr, self.data
+		)
+	}
+}
+
+#[derive(Clone)]
+pub struct Filter {
+	pub range: Option<RangeInclusive<u16>>, // None for the IO registers
+	pub names: Vec<String>,                 // register names, empty for any
+	pub reads: bool,
+	pub writes: bool,
+	pub frames: Option<RangeInclusive<u64>>,
+}
+impl std::default::Default for Filter {
+	fn default() -> Filter {
+		Filter {
+			range: None,
+			names: vec![],
+			reads: true,
+			writes: true,
+			frames: None,
+		}
+	}
+}
+impl Filter {
+	pub fn matches(&self, addr: u16, write: bool, frame: u64) -> bool {
+		let in_range = match &self.range {
+			Some(range) => range.contains(&addr),
+			None => matches!(addr, 0xFF00..=0xFF7F | 0xFFFF),
+		};
+		if !in_range || !(if write { self.writes } else { self.reads }) {
+			return false;
+		}
+		if let Some(frames) = &self.frames
+			&& !frames.contains(&frame)
+		{
+			return false;
+		}
+		self.names.is_empty() || self.names.iter().any(|name| register_is(addr, name))
+	}
+
+	/// Set the access kinds from "r", "w" or "rw"
+	pub fn set_kind(&mut self, kind: &str) -> Result<(), String> {
+		(self.reads, self.writes) = match kind {
+			"r" => (true, false),
+			"w" => (false, true),
+			"rw" => (true, true),
+			_ => return Err(format!("Unknown access kind {kind}, expected r, w or rw")),
+		};
+		Ok(())
+	}
+}
+
+// Names like "P1/JOYP" match either half, ignoring case
+fn register_is(addr: u16, name: &str) -> bool {
+	name_of(addr as usize)
+		.split(['/', ' '])
+		.any(|part| part.eq_ignore_ascii_case(name))
+}
+
+#[derive(Default)]
+pub struct IoLog {
+	pub filter: Filter,
+	pub ring: VecDeque<Event>,
+	out: Option<BufWriter<File>>,
+}
+impl IoLog {
+	pub fn create(path: &str) -> std::io::Result<IoLog> {
+		Ok(IoLog {
+			out: Some(BufWriter::new(File::create(path)?)),
+			..IoLog::default()
+		})
+	}
+
+	pub fn record(&mut self, event: Event) {
+		if let Some(out) = &mut self.out
+			&& let Err(e) = writeln!(out, "{event}")
+		{
+			warn!(target: "tools", "IO log file stopped: {e}");
+			self.out = None;
+		}
+		if self.ring.len() == RING_LEN {
+			self.ring.pop_front();
+		}
+		self.ring.push_back(event);
+	}
+
+	/// Write out buffered events, for when the log stops or won't be dropped normally
+	pub fn flush(&mut self) -> std::io::Result<()> {
+		match &mut self.out {
+			Some(out) => out.flush(),
+			None => Ok(()),
+		}
+	}
+
+	/// The last count events, oldest first
+	pub fn recent(&self, count: usize) -> Vec<String> {
+		let skip = self.ring.len().saturating_sub(count);
+		self.ring.iter().skip(skip).map(Event::to_string).collect()
+	}
+}
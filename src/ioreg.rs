@@ -1,4 +1,8 @@
 use crate::audio::AudioParams;
+use crate::model::Model;
+use crate::sgb::SGB;
+use crate::video::DmgPalettes;
+use log::{trace, warn};
 
 pub const INT_VBLANK: u8 = 1;
 pub const INT_LCD: u8 = 2;
@@ -6,7 +10,7 @@ pub const INT_TIMER: u8 = 4;
 pub const INT_SERIAL: u8 = 8;
 pub const INT_JOYPAD: u8 = 16;
 
-fn name_of(addr: usize) -> &'static str {
+pub fn name_of(addr: usize) -> &'static str {
 	match addr {
 		0xFF00 => "P1/JOYP",          // Joypad
 		0xFF01 => "SB",               // Serial transfer data
@@ -79,6 +83,9 @@ fn name_of(addr: usize) -> &'static str {
 #[derive(Default)]
 pub struct DivRegister(u16);
 impl DivRegister {
+	pub fn from_counter(counter: u16) -> DivRegister {
+		DivRegister(counter)
+	}
 	pub fn get(&self) -> u8 {
 		(self.0 >> 8) as u8
 	}
@@ -88,16 +95,22 @@ impl DivRegister {
 	fn tick_mcycle(&mut self) {
 		self.0 = self.0.wrapping_add(4);
 	}
-	fn should_increment_timer(&self, tac: u8) -> bool {
+	// Timer input: the counter bit selected by TAC, gated by the enable bit
+	fn timer_bit(&self, tac: u8) -> bool {
 		if tac & 0b100 == 0 {
 			return false;
 		}
-		0 == match tac & 0b11 {
-			0 => self.0 & 1023,
-			1 => self.0 & 15,
-			2 => self.0 & 63,
-			_ => self.0 & 255,
-		}
+		let bit = match tac & 0b11 {
+			0 => 9,
+			1 => 3,
+			2 => 5,
+			_ => 7,
+		};
+		(self.0 >> bit) & 1 != 0
+	}
+	// Serial clock: 8192 Hz
+	fn serial_bit(&self) -> bool {
+		(self.0 >> 8) & 1 != 0
 	}
 }
 
@@ -105,10 +118,14 @@ impl DivRegister {
 pub struct IoReg {
 	// normal io registers
 	pub p1_joyp: u8,
+	pub sb: u8,
+	pub sc: u8,
 	pub div: DivRegister,
 	pub tima: u8,
 	pub tma: u8,
 	pub tac: u8,
+	pub tima_overflow: bool,  // TIMA wrapped to 0, reload is due next M-cycle
+	pub tima_reloading: bool, // TIMA was reloaded from TMA this M-cycle
 	pub interrupt: u8,
 	pub lcdc: u8,
 	pub stat: u8,
@@ -122,37 +139,71 @@ pub struct IoReg {
 	pub wy: u8,
 	pub wx: u8,
 	pub hide_boot_rom: bool,
+	pub bcps: u8,
+	pub ocps: u8,
 	pub ie: u8,
 
+	// CGB palette memory, 8 palettes of 4 RGB555 colors
+	pub bg_palette_ram: [[u8; 8]; 8],
+	pub obj_palette_ram: [[u8; 8]; 8],
+
 	pub audio_params: AudioParams,
+	pub sgb: SGB,
 
 	// other
 	pub joyc: bool, // not documented in pandocs
 
 	// not io registers
-	pub debug: bool,
+	pub doctor_ly: bool,
+	pub model: Model,
+	pub dmg_compat: bool, // DMG cartridge running on CGB hardware
+	pub dmg_palettes: DmgPalettes,
 	pub user_input_buttons: u8,
 	pub user_input_joypad: u8,
+	pub joypad_lines: u8, // P1 input lines as last seen, to find falling edges
 	pub lx: u64,
+	pub serial_bits: u8,             // left to shift in the current transfer
+	pub serial_out: Option<Vec<u8>>, // bytes sent, kept when something reads them
 }
 impl IoReg {
+	/// The low 4 bits of P1: 0 for each pressed button in the selected groups
+	pub fn p1_inputs(&self) -> u8 {
+		// other SGB players have no controllers connected
+		let (buttons, joypad) = match self.sgb.current_player {
+			0 => (
+				0xf & !self.user_input_buttons,
+				0xf & !self.user_input_joypad,
+			),
+			_ => (0xf, 0xf),
+		};
+		match self.p1_joyp & 0b11_0000 {
+			// SGB reports the selected joypad here
+			0b11_0000 => 0xf - self.sgb.current_player,
+			0b01_0000 => buttons,
+			0b10_0000 => joypad,
+			_ => buttons & joypad,
+		}
+	}
+	/// Request the joypad interrupt when an input line goes from high to low,
+	/// from a button press or from selecting a group with a button held.
+	pub fn update_joypad(&mut self) {
+		let lines = self.p1_inputs();
+		if self.joypad_lines & !lines != 0 {
+			self.interrupt |= INT_JOYPAD;
+		}
+		self.joypad_lines = lines;
+	}
 	pub fn get(&self, addr: usize) -> u8 {
 		let r = match addr {
-			0xFF00 => {
-				let target = self.p1_joyp & 0b11_0000;
-				let buttons = 0xf & !self.user_input_buttons;
-				let joypad = 0xf & !self.user_input_joypad;
-				target
-					| match target {
-						0b11_0000 => 0xf,
-						0b01_0000 => buttons,
-						0b10_0000 => joypad,
-						_ => buttons & joypad,
-					}
-			}
+			// the top two bits are unused and read as 1
+			0xFF00 => 0b1100_0000 | (self.p1_joyp & 0b11_0000) | self.p1_inputs(),
+			0xFF01 => self.sb,
+			0xFF02 => 0x7E | self.sc,
 			0xFF04 => self.div.get(),
 			0xFF05 => self.tima,
-			0xFF0F => self.interrupt,
+			0xFF06 => self.tma,
+			0xFF07 => 0xF8 | self.tac,
+			0xFF0F => 0xE0 | self.interrupt, // the top 3 bits are unused and read as 1
 			0xFF40 => self.lcdc,
 
 			// TODO: report PPU mode
@@ -160,35 +211,80 @@ impl IoReg {
 
 			0xFF42 => self.scy,
 			0xFF43 => self.scx,
+			// Gameboy Doctor logs are made with LY stuck at 0x90
+			0xFF44 if self.doctor_ly => 0x90,
 			0xFF44 => self.ly,
 			0xFF45 => self.lyc,
 			0xFF4a => self.wy,
 			0xFF4b => self.wx,
 			0xFF50 => self.hide_boot_rom as u8,
+			// palette memory is locked after the boot rom in DMG compatibility mode
+			0xFF68 if self.dmg_compat && !self.hide_boot_rom => self.bcps,
+			0xFF69 if self.dmg_compat && !self.hide_boot_rom => {
+				let i = self.bcps as usize & 0x3f;
+				self.bg_palette_ram[i >> 3][i & 7]
+			}
+			0xFF6A if self.dmg_compat && !self.hide_boot_rom => self.ocps,
+			0xFF6B if self.dmg_compat && !self.hide_boot_rom => {
+				let i = self.ocps as usize & 0x3f;
+				self.obj_palette_ram[i >> 3][i & 7]
+			}
 			// 0xFF60 => 0xff,
 			0xFFFF => self.ie,
 			_ => {
-				println!("Read from unknown IO register {addr:#x?}");
+				warn!(target: "io", "Read from unknown IO register {addr:#x?}");
 				0xff
 			}
 		};
-		if self.debug {
-			println!("IO read from {}: {r:02x}", name_of(addr));
-		}
+		trace!(target: "io", "IO read from {}: {r:02x}", name_of(addr));
 		r
 	}
 	pub fn set(&mut self, addr: usize, data: u8) {
-		if self.debug {
-			println!("IO write to [{}] = {data:#x?}", name_of(addr));
-		}
+		trace!(target: "io", "IO write to [{}] = {data:#x?}", name_of(addr));
 		match addr {
-			0xFF00 => self.p1_joyp = data,
-			0xFF04 => self.div.reset(),
-			0xFF05 => self.tima = data,
-			0xFF06 => self.tma = data,
-			0xFF07 => self.tac = data,
-			0xFF0F => self.interrupt = data,
-			0xFF10..=0xFF3F => self.audio_params.set(addr, data),
+			0xFF00 => {
+				if self.sgb.enabled {
+					self.sgb.write_p1(self.p1_joyp, data);
+				}
+				self.p1_joyp = data;
+			}
+			0xFF01 => self.sb = data,
+			0xFF02 => {
+				self.sc = data & 0x81;
+				// With no link partner, a transfer on the external clock never ends
+				if self.sc & 0x81 == 0x81 {
+					trace!(target: "io", "serial transfer of {:02x}", self.sb);
+					if let Some(out) = &mut self.serial_out {
+						out.push(self.sb);
+					}
+					self.serial_bits = 8;
+				}
+			}
+			0xFF04 => {
+				let before = self.div.timer_bit(self.tac);
+				self.div.reset();
+				self.timer_edge(before);
+			}
+			0xFF05 => {
+				// ignored while reloading, and cancels a pending reload
+				if !self.tima_reloading {
+					self.tima = data;
+					self.tima_overflow = false;
+				}
+			}
+			0xFF06 => {
+				self.tma = data;
+				if self.tima_reloading {
+					self.tima = data;
+				}
+			}
+			0xFF07 => {
+				let before = self.div.timer_bit(self.tac);
+				self.tac = data & 0b111;
+				self.timer_edge(before);
+			}
+			0xFF0F => self.interrupt = data & 0x1F,
+			0xFF10..=0xFF3F => self.audio_params.set(addr, data, self.model),
 			0xFF40 => {
 				if data & 0x80 == 0 {
 					self.lx = 0;
@@ -199,6 +295,13 @@ impl IoReg {
 			0xFF41 => {
 				// we dont support mode 0/1/2 int select
 				assert!(data & 0b111000 == 0);
+				// Pre-CGB models briefly enable every STAT source on a write,
+				// which raises the interrupt in HBlank, VBlank or on LY=LYC
+				let hblank = self.lx >= 80 + 172; // after the shortest mode 3
+				let source = self.ly >= 144 || hblank || self.ly == self.lyc;
+				if !self.model.is_cgb() && self.lcdc & 0x80 != 0 && source {
+					self.interrupt |= INT_LCD;
+				}
 				self.stat = data;
 			}
 			0xFF42 => self.scy = data,
@@ -214,6 +317,22 @@ impl IoReg {
 					self.hide_boot_rom = true;
 				}
 			}
+			0xFF68 if self.dmg_compat && !self.hide_boot_rom => self.bcps = data & 0b1011_1111,
+			0xFF69 if self.dmg_compat && !self.hide_boot_rom => {
+				let i = self.bcps as usize & 0x3f;
+				self.bg_palette_ram[i >> 3][i & 7] = data;
+				if self.bcps & 0x80 != 0 {
+					self.bcps = 0x80 | ((self.bcps + 1) & 0x3f);
+				}
+			}
+			0xFF6A if self.dmg_compat && !self.hide_boot_rom => self.ocps = data & 0b1011_1111,
+			0xFF6B if self.dmg_compat && !self.hide_boot_rom => {
+				let i = self.ocps as usize & 0x3f;
+				self.obj_palette_ram[i >> 3][i & 7] = data;
+				if self.ocps & 0x80 != 0 {
+					self.ocps = 0x80 | ((self.ocps + 1) & 0x3f);
+				}
+			}
 			0xFF60 => {
 				let value = data & 1 != 0;
 				// 1-bit register. 0 is the default (normal) value,
@@ -222,26 +341,47 @@ impl IoReg {
 			}
 			0xFF7F => {} // Unmapped
 			0xFFFF => self.ie = data,
-			_ => println!("Write to unknown IO register [{addr:#x?}] = {data:#x?}"),
+			_ => warn!(target: "io", "Write to unknown IO register [{addr:#x?}] = {data:#x?}"),
 		};
 	}
-	pub fn advance_counter_div(&mut self, mcycles: u64) -> bool {
-		// TODO: sys counter resets and stops incrementing in stop mode,
-		// but TIMA needs to keep going.
-
-		let mut do_interrupt = false;
+	pub fn advance_counter_div(&mut self, mcycles: u64) {
 		for _ in 0..mcycles {
+			self.tima_reloading = false;
+			if self.tima_overflow {
+				self.tima_overflow = false;
+				self.tima_reloading = true;
+				self.tima = self.tma;
+				self.interrupt |= INT_TIMER;
+				trace!(target: "timer", "TIMA overflow, reloaded {:02x}", self.tma);
+			}
+
+			let before = self.div.timer_bit(self.tac);
+			let serial_before = self.div.serial_bit();
 			self.div.tick_mcycle();
-			if self.div.should_increment_timer(self.tac) {
-				if self.tima == 0xff {
-					self.tima = self.tma;
-					self.interrupt |= INT_TIMER;
-					do_interrupt = true;
-				} else {
-					self.tima += 1;
-				}
+			self.timer_edge(before);
+			self.serial_edge(serial_before);
+		}
+	}
+	// The internal clock shifts SB out a bit at a time. Nothing is connected,
+	// so 1s are shifted in and the byte received is 0xFF.
+	fn serial_edge(&mut self, before: bool) {
+		if self.serial_bits == 0 || !before || self.div.serial_bit() {
+			return;
+		}
+		self.sb = (self.sb << 1) | 1;
+		self.serial_bits -= 1;
+		if self.serial_bits == 0 {
+			self.sc &= 0x7F;
+			self.interrupt |= INT_SERIAL;
+		}
+	}
+	// TIMA counts falling edges of the timer input, so writing DIV or TAC can tick it too
+	fn timer_edge(&mut self, before: bool) {
+		if before && !self.div.timer_bit(self.tac) {
+			self.tima = self.tima.wrapping_add(1);
+			if self.tima == 0 {
+				self.tima_overflow = true;
 			}
 		}
-		do_interrupt
 	}
 }
@@ -0,0 +1,148 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::HashMap;
+
+// Logger behind the log crate macros. Each subsystem logs under its own target
+// and the levels come from a spec like "warn,cpu=trace,io=fatal", given in
+// the GB_LOG environment variable or with --log. "fatal" logs like "warn" but
+// panics on warnings and errors, for tests that must not hit them.
+//
+// The panic happens inside Log::log, on the thread that logged. Emulation
+// logs in the middle of a step, with run holding the GB mutex, so the mutex is
+// poisoned on the way out. run's panic path prints the guest backtrace and
+// saves what it can before the panic carries on.
+//
+// sgb is the Super Game Boy packets, ui the window and input, and tools the
+// debugging tools: symbols, code/data log, IO log, profiler, GDB and movies.
+
+pub const TARGETS: [&str; 9] = [
+	"cpu", "ppu", "apu", "timer", "cart", "io", "sgb", "ui", "tools",
+];
+pub const ENV_VAR: &str = "GB_LOG";
+
+#[derive(Clone, Copy)]
+struct Rule {
+	level: LevelFilter,
+	fatal: bool,
+}
+
+pub struct Logger {
+	default: Rule,
+	targets: HashMap<String, Rule>,
+}
+impl std::default::Default for Logger {
+	fn default() -> Logger {
+		Logger {
+			default: Rule {
+				level: LevelFilter::Info,
+				fatal: false,
+			},
+			targets: HashMap::new(),
+		}
+	}
+}
+impl Logger {
+	/// Apply a spec on top of the current levels
+	pub fn parse(&mut self, spec: &str) -> Result<(), String> {
+		for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+			match part.split_once('=') {
+				Some((target, level)) => {
+					if !TARGETS.contains(&target) {
+						return Err(format!(
+							"Unknown log target {target}, expected one of {}",
+							TARGETS.join(", ")
+						));
+					}
+					self.targets.insert(target.into(), parse_rule(level)?);
+				}
+				None => self.default = parse_rule(part)?,
+			}
+		}
+		Ok(())
+	}
+
+	/// Install as the global logger
+	pub fn init(self) -> Result<(), String> {
+		let max = self
+			.targets
+			.values()
+			.map(|rule| rule.level)
+			.fold(self.default.level, Ord::max);
+		log::set_logger(Box::leak(Box::new(self))).map_err(|e| e.to_string())?;
+		log::set_max_level(max);
+		Ok(())
+	}
+
+	fn rule(&self, target: &str) -> Rule {
+		self.targets.get(target).copied().unwrap_or(self.default)
+	}
+}
+impl Log for Logger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		metadata.level() <= self.rule(metadata.target()).level
+	}
+
+	fn log(&self, record: &Record) {
+		let rule = self.rule(record.target());
+		if record.level() > rule.level {
+			return;
+		}
+		if rule.fatal && record.level() <= Level::Warn {
+			panic!("[{}] {}", record.target(), record.args());
+		}
+		eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+	}
+
+	fn flush(&self) {}
+}
+
+fn parse_rule(level: &str) -> Result<Rule, String> {
+	if level.eq_ignore_ascii_case("fatal") {
+		return Ok(Rule {
+			level: LevelFilter::Warn,
+			fatal: true,
+		});
+	}
+	let level = level.parse().map_err(|_| {
+		format!("Unknown log level {level}, expected off, error, warn, info, debug, trace or fatal")
+	})?;
+	Ok(Rule {
+		level,
+		fatal: false,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn levels_per_target() {
+		let mut logger = Logger::default();
+		logger.parse("warn, cpu=trace,io=fatal").unwrap();
+		assert_eq!(logger.rule("cpu").level, LevelFilter::Trace);
+		assert_eq!(logger.rule("ppu").level, LevelFilter::Warn);
+		assert!(logger.rule("io").fatal);
+		assert!(!logger.rule("cpu").fatal);
+	}
+
+	#[test]
+	fn specs_apply_on_top() {
+		let mut logger = Logger::default();
+		logger.parse("cpu=debug").unwrap();
+		logger.parse("error").unwrap();
+		assert_eq!(logger.rule("cpu").level, LevelFilter::Debug);
+		assert_eq!(logger.rule("apu").level, LevelFilter::Error);
+	}
+
+	#[test]
+	fn unknown_target() {
+		let err = Logger::default().parse("gpu=trace").unwrap_err();
+		assert!(err.starts_with("Unknown log target gpu"), "{err}");
+	}
+
+	#[test]
+	fn unknown_level() {
+		let err = Logger::default().parse("cpu=loud").unwrap_err();
+		assert!(err.starts_with("Unknown log level loud"), "{err}");
+	}
+}
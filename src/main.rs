@@ -1,31 +1,50 @@
+use log::warn;
 use std::error::Error;
-use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 pub mod audio;
+pub mod boot;
 pub mod bus;
+pub mod callstack;
 pub mod cart;
+pub mod cdl;
+pub mod cli;
+pub mod compat;
+pub mod config;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod iolog;
 pub mod ioreg;
+pub mod logging;
+pub mod model;
+pub mod movie;
+pub mod profiler;
+pub mod sgb;
+pub mod symbols;
+pub mod trace;
 pub mod ui;
 pub mod video;
 
 const DOTS_HZ: u32 = 1 << 22;
+const DOTS_PER_FRAME: u64 = 70224;
 
+#[derive(Default)]
 pub struct GB {
 	bus: bus::Bus,
 	cpu: cpu::CPU,
-	framebuffer: [u8; 160 * 144 * 3],
 }
-impl std::default::Default for GB {
-	fn default() -> GB {
-		GB {
-			bus: bus::Bus::default(),
-			cpu: cpu::CPU::default(),
-			framebuffer: [30; 160 * 144 * 3],
-		}
+
+impl GB {
+	pub fn set_model(&mut self, model: model::Model) {
+		self.bus.io.model = model;
+		// Only DMG cartridges are supported, so CGB hardware runs them in compatibility mode
+		self.bus.io.dmg_compat = model.is_cgb();
+		self.bus.io.sgb.enabled = model.is_sgb() && sgb::SGB::supported_by(&self.bus.cart.rom[0]);
 	}
 }
 
@@ -35,99 +54,488 @@ fn slow_down(real_elapsed: Duration, elapsed_dots: u64) {
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-	let mut gb = GB::default();
-	let mut rom: Vec<u8> = vec![];
-
-	let audio_device = audio::init_audio();
-	let mut apu = audio::APU::new(&audio_device);
-
-	let argv: Vec<String> = std::env::args().collect();
-	for arg in &argv[1..] {
-		let mut arg_iter = arg.chars();
-		if arg_iter.next().unwrap_or_default() == '-' {
-			for arg_char in arg_iter {
-				match arg_char {
-					'c' => gb.cpu.debug = true,
-					'i' => gb.bus.io.debug = true,
-					'b' => gb.bus.cart.debug_bank_switch = true,
-					_ => panic!("Bad commandline flag: {arg}"),
-				}
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	let command = match cli::parse(&args) {
+		Ok(command) => command,
+		Err(e) => {
+			eprintln!(
+				"error: {e}\n\n{}\n\nRun \"gameboy help\" for the options.",
+				cli::USAGE
+			);
+			std::process::exit(2);
+		}
+	};
+	match command {
+		cli::Command::Help => println!("{}\n\n{}", cli::USAGE, cli::HELP),
+		cli::Command::Info(rom) => info_command(&rom)?,
+		cli::Command::Disasm { rom, bank, range } => disasm_command(&rom, bank, range)?,
+		cli::Command::Run(options) => {
+			init_logging(&options)?;
+			let config = config::Config::load(options.config.as_deref())?;
+			run(&options, &config)?
+		}
+		cli::Command::Test(options) => {
+			init_logging(&options)?;
+			let config = config::Config::load(options.config.as_deref())?;
+			if !test_command(&options, &config)? {
+				std::process::exit(1);
 			}
+		}
+	}
+	Ok(())
+}
+
+// info <rom>
+fn info_command(path: &str) -> Result<(), Box<dyn Error>> {
+	let rom = std::fs::read(path)?;
+	if rom.len() < 0x150 {
+		return Err(format!("{path} is too small to be a Game Boy ROM").into());
+	}
+	let title: String = rom[0x134..0x144]
+		.iter()
+		.take_while(|&&b| b != 0)
+		.map(|&b| b as char)
+		.collect();
+	let header_checksum = rom[0x134..0x14D]
+		.iter()
+		.fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1));
+	let global_checksum = rom
+		.iter()
+		.enumerate()
+		.filter(|&(i, _)| i != 0x14E && i != 0x14F)
+		.fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+	let check = |ok: bool| if ok { "ok" } else { "bad" };
+
+	println!("Title:           {title}");
+	println!(
+		"CGB flag:        {:02x} ({})",
+		rom[0x143],
+		match rom[0x143] {
+			0xC0 => "CGB only",
+			0x80 => "CGB enhanced",
+			_ => "DMG",
+		}
+	);
+	println!(
+		"SGB flag:        {:02x} ({})",
+		rom[0x146],
+		if sgb::SGB::supported_by(&rom) {
+			"SGB functions"
 		} else {
-			rom = std::fs::File::open(&std::path::Path::new(arg))?
-				.bytes()
-				.collect::<Result<Vec<_>, _>>()?;
+			"none"
 		}
+	);
+	println!(
+		"Cartridge type:  {:02x} ({})",
+		rom[0x147],
+		cart::type_name(rom[0x147])
+	);
+	let rom_banks = match rom[0x148] {
+		code @ 0x00..=0x08 => Some(2u32 << code),
+		0x52 => Some(72),
+		0x53 => Some(80),
+		0x54 => Some(96),
+		_ => None,
+	};
+	match rom_banks {
+		Some(banks) => println!(
+			"ROM size:        {:02x} ({} KiB, {banks} banks)",
+			rom[0x148],
+			banks * 16
+		),
+		None => println!("ROM size:        {:02x} (unknown)", rom[0x148]),
+	}
+	let ram_kib = match rom[0x149] {
+		2 => 8,
+		3 => 32,
+		4 => 128,
+		5 => 64,
+		_ => 0,
+	};
+	println!("RAM size:        {:02x} ({ram_kib} KiB)", rom[0x149]);
+	println!(
+		"Destination:     {:02x} ({})",
+		rom[0x14A],
+		if rom[0x14A] == 0 { "Japan" } else { "overseas" }
+	);
+	match rom[0x14B] {
+		0x33 => println!(
+			"Licensee:        {}",
+			String::from_utf8_lossy(&rom[0x144..0x146])
+		),
+		old => println!("Licensee:        {old:02x}"),
 	}
+	println!("Version:         {:02x}", rom[0x14C]);
+	println!(
+		"Header checksum: {:02x} ({})",
+		rom[0x14D],
+		check(rom[0x14D] == header_checksum)
+	);
+	println!(
+		"Global checksum: {:04x} ({})",
+		u16::from_be_bytes([rom[0x14E], rom[0x14F]]),
+		check(u16::from_be_bytes([rom[0x14E], rom[0x14F]]) == global_checksum)
+	);
+	println!("Size:            {} bytes", rom.len());
+	println!("Default model:   {:?}", model::Model::from_header(&rom));
+	Ok(())
+}
+
+// disasm <rom> [bank] [start-end]
+fn disasm_command(
+	path: &str,
+	bank: Option<usize>,
+	range: Option<(u16, u16)>,
+) -> Result<(), Box<dyn Error>> {
+	let rom = std::fs::read(path)?;
+	let symbols = symbols::Symbols::load_for_rom(path).unwrap_or_default();
+	let cdl = cdl::Cdl::load_for_rom(path);
+	let mut cart = cart::Cartridge::default();
+	cart.load_rom(&rom)?;
 
-	let mut ui = ui::UI::new(false)?;
+	let banks = match bank {
+		Some(bank) if bank >= cart.rom.len() => {
+			return Err(format!("ROM only has {} banks", cart.rom.len()).into());
+		}
+		Some(bank) => bank..bank + 1,
+		None => 0..cart.rom.len(),
+	};
+	for bank in banks {
+		let window = if bank == 0 { 0x0000 } else { 0x4000 };
+		let (start, end) = range.unwrap_or((window, window + 0x3FFF));
+		for line in disasm::dump_bank(&cart.rom, &symbols, cdl.as_ref(), bank, start, end) {
+			println!("{line}");
+		}
+	}
+	Ok(())
+}
 
-	assert!(rom.len() > 0);
+/// Battery saves are named after the ROM
+fn save_path(options: &cli::Options, config: &config::Config) -> PathBuf {
+	let rom = Path::new(&options.rom);
+	let dir = match options.save_dir.as_ref().or(config.save_dir.as_ref()) {
+		Some(dir) => dir.as_path(),
+		None => rom.parent().unwrap_or(Path::new(".")),
+	};
+	dir.join(rom.with_extension("sav").file_name().unwrap_or_default())
+}
+
+/// Start logging before anything else, so loading the config can log too
+fn init_logging(options: &cli::Options) -> Result<(), Box<dyn Error>> {
+	let mut logger = logging::Logger::default();
+	if let Ok(spec) = std::env::var(logging::ENV_VAR) {
+		logger.parse(&spec)?;
+	}
+	for spec in &options.log {
+		logger.parse(spec)?;
+	}
+	Ok(logger.init()?)
+}
+
+/// Set up the system and the debugging aids the options ask for
+fn load(options: &cli::Options, config: &config::Config) -> Result<GB, Box<dyn Error>> {
+	let mut gb = GB::default();
+	let rom = std::fs::read(&options.rom).map_err(|e| format!("{}: {e}", options.rom))?;
+	if rom.len() < 0x150 {
+		return Err(format!("{} is too small to be a Game Boy ROM", options.rom).into());
+	}
 	gb.bus.cart.load_rom(&rom)?;
+	if let Some(symbols) = symbols::Symbols::load_for_rom(&options.rom) {
+		gb.bus.symbols = symbols;
+	}
+	// movies start with empty cartridge RAM
+	let movie = options.record.is_some() || options.play.is_some();
+	if gb.bus.cart.battery
+		&& !movie
+		&& let Ok(data) = std::fs::read(save_path(options, config))
+	{
+		gb.bus.cart.load_ram(&data);
+	}
+
+	let model = options
+		.model
+		.unwrap_or_else(|| model::Model::from_header(&rom));
+	if model.is_sgb() && !sgb::SGB::supported_by(&rom) {
+		warn!(target: "sgb", "Cartridge does not support SGB functions");
+	}
+	gb.set_model(model);
+	gb.bus.io.dmg_palettes = video::PRESETS[config.palette].1;
+
+	let boot_rom = match &options.boot_rom {
+		Some(path) => Some(PathBuf::from(path)),
+		None => config.boot_rom(model).map(Path::to_path_buf),
+	};
+	match boot_rom {
+		Some(path) => {
+			let data = std::fs::read(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+			boot::load_boot_rom(&mut gb, data)?
+		}
+		None => boot::skip(&mut gb),
+	}
+
+	if let Some(path) = &options.trace {
+		let mut trace = trace::Trace::create(path)?;
+		trace.pc_range = options.trace_range.clone();
+		trace.remaining = options.trace_count;
+		gb.cpu.trace = Some(trace);
+	}
+	// match the conditions Gameboy Doctor logs are made under
+	gb.bus.io.doctor_ly = options.doctor;
+	if let Some(path) = &options.io_log {
+		let mut log = iolog::IoLog::create(path)?;
+		log.filter = options.io_log_filter.clone();
+		gb.bus.io_log = Some(log.into());
+	}
+	if options.cdl {
+		gb.bus.cdl = Some(cdl::Cdl::open(
+			&options.rom,
+			gb.bus.cart.rom.len() * cart::ROM_BANK_SIZE,
+		));
+	}
+	Ok(gb)
+}
+
+fn run(options: &cli::Options, config: &config::Config) -> Result<(), Box<dyn Error>> {
+	let gb = load(options, config)?;
+
+	let mut debugger = debugger::Debugger::default();
+	if options.debug {
+		debugger.start_repl();
+		debugger.pause();
+	}
+	let mut profiler = options.profile.as_deref().map(profiler::Profiler::new);
+	let mut movie = match (&options.record, &options.play) {
+		(Some(path), _) => Some(movie::Movie::record(path, &gb)?),
+		(_, Some(path)) => Some(movie::Movie::play(path, &gb)?),
+		_ => None,
+	};
+
+	// Start paused until the debugger says otherwise
+	let mut gdb = match options.gdb {
+		Some(port) => {
+			debugger.pause();
+			Some(gdb::GdbStub::listen(port)?)
+		}
+		None => None,
+	};
+
+	// Headless runs have no window or sound, and don't wait for real time
+	let audio_device = match options.headless {
+		true => None,
+		false => Some(audio::init_audio()),
+	};
+	let volume = if options.mute { 0.0 } else { config.volume };
+	let mut apu = audio_device
+		.as_ref()
+		.map(|device| audio::APU::new(device, volume));
+	let mut ui = match options.headless {
+		true => None,
+		false => Some(ui::UI::new(
+			false,
+			gb.bus.io.sgb.enabled,
+			options.scale.unwrap_or(config.scale),
+			config,
+		)?),
+	};
 
 	let lgb = Arc::new(Mutex::new(gb));
 
-	let start = Instant::now();
-	const DOTS_PER_SCANLINE: u64 = 456;
-	let mut sprites = vec![];
+	let mut start = Instant::now();
 	let mut dots = 0;
-	let mut dots_cpu = 0;
 
-	let mut play = true;
-	while play {
-		slow_down(start.elapsed(), dots);
+	// Battery RAM is written however the loop ends, so an error doesn't lose the save
+	let emulated = (|| -> Result<(), Box<dyn Error>> {
+		let mut play = true;
+		while play {
+			if !options.headless {
+				slow_down(start.elapsed(), dots);
+			}
 
-		let mut gb = lgb.lock().map_err(|x| x.to_string())?;
+			let mut gb = lgb.lock().map_err(|x| x.to_string())?;
 
-		ui.draw(&mut gb, &mut play)?;
+			if let Some(stub) = &mut gdb {
+				stub.poll(&mut gb, &mut debugger)?;
+				if stub.killed {
+					break;
+				}
+			}
+			// GDB answers its own interrupts, but has to hear about pauses from
+			// the REPL and the window too
+			let was_paused = debugger.paused;
+			debugger.poll_repl(&mut gb);
+			if let Some(ui) = &mut ui {
+				ui.draw(&mut gb, &mut debugger, &mut play)?;
+			}
+			if debugger.paused
+				&& !was_paused
+				&& let Some(stub) = &mut gdb
+			{
+				stub.report_stop(&debugger::Stop::Pause, &mut debugger);
+			}
 
-		while play {
-			dots += 1;
-
-			// Advance screen
-			if gb.bus.io.lcdc & 0x80 != 0 {
-				if gb.bus.io.lx >= DOTS_PER_SCANLINE {
-					gb.bus.io.lx = 0;
-					gb.bus.io.ly += 1;
-					if gb.bus.io.ly >= 154 {
-						gb.bus.io.ly = 0;
-					}
+			if debugger.paused {
+				// Don't let emulated time fall behind while paused
+				const PAUSED_POLL: Duration = Duration::from_millis(16);
+				sleep(PAUSED_POLL);
+				start += PAUSED_POLL;
+				continue;
+			}
 
-					if gb.bus.io.ly == gb.bus.io.lyc {
-						gb.bus.io.interrupt |= ioreg::INT_LCD;
-						gb.cpu.halt = false; // TODO: this should wake the system, right?
-					}
+			if let Some(movie) = &mut movie {
+				movie.update(&mut gb)?;
+				if movie.finished && options.headless {
+					break;
+				}
+			}
 
-					if gb.bus.io.ly == 144 {
-						gb.bus.io.interrupt |= ioreg::INT_VBLANK;
-						gb.cpu.halt = false;
-						break;
+			let frame_start = dots;
+			loop {
+				if let Some(profiler) = &mut profiler {
+					profiler.begin(&gb);
+				}
+				// The CPU advances the rest of the system as it accesses memory
+				let cycle = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+					debugger.cycle(&mut gb)
+				}));
+				let (mcycles, stop) = match cycle {
+					Ok(result) => result,
+					Err(panic) => {
+						println!("{}", gb.cpu.calls.report(&gb.bus, gb.cpu.pc));
+						if let Some(trace) = &mut gb.cpu.trace {
+							trace.flush()?;
+						}
+						if let Some(log) = &gb.bus.io_log {
+							log.borrow_mut().flush()?;
+						}
+						if let Some(cdl) = &gb.bus.cdl {
+							cdl.save()?;
+						}
+						if movie.is_none() {
+							save_battery(&gb, options, config)?;
+						}
+						std::panic::resume_unwind(panic);
 					}
+				};
+				if let Some(profiler) = &mut profiler {
+					profiler.end(mcycles);
 				}
-				if gb.bus.io.lx == 80 {
-					sprites = video::oam_scan(&gb);
+				if let Some(stop) = stop {
+					println!("{}", debugger.describe(&stop, &gb));
+					if let Some(stub) = &mut gdb {
+						stub.report_stop(&stop, &mut debugger);
+					}
 				}
-				{
-					let tmp = gb.bus.io.lx;
-					video::render_dot(&mut gb, tmp, &sprites);
+				for _ in 0..mcycles * 4 {
+					dots += 1;
+					if let Some(apu) = &mut apu {
+						apu.tick(&mut gb, dots)?;
+					}
 				}
-				gb.bus.io.lx += 1;
-			} else if dots & 0xffff == 0 {
-				// if lcd is off, break "sometimes" to draw
-				break;
-			}
 
-			apu.tick(&mut gb, dots)?;
-
-			// Advance CPU
-			if dots_cpu < dots {
-				let mcycles = cpu::cycle(&mut gb);
-				dots_cpu += mcycles * 4;
-				if gb.bus.io.advance_counter_div(mcycles) {
-					gb.cpu.halt = false;
+				if debugger.paused {
+					break;
+				}
+				if gb.bus.frame_done {
+					gb.bus.frame_done = false;
+					break;
+				}
+				if (gb.bus.io.lcdc & 0x80 == 0 || gb.cpu.stopped) && dots - frame_start >= 0x10000 {
+					// if lcd is off or stopped, break "sometimes" to draw
+					break;
 				}
 			}
+
+			if let Some(frames) = options.frames
+				&& dots >= frames * DOTS_PER_FRAME
+			{
+				play = false;
+			}
 		}
+		Ok(())
+	})();
+	let gb = lgb.lock().map_err(|x| x.to_string())?;
+	if movie.is_none() {
+		save_battery(&gb, options, config)?;
 	}
+	emulated?;
+
+	if let Some(profiler) = &profiler {
+		profiler.finish(&gb.bus.symbols)?;
+	}
+	if let Some(cdl) = &gb.bus.cdl {
+		cdl.save()?;
+	}
+	if let Some(log) = &gb.bus.io_log {
+		log.borrow_mut().flush()?;
+	}
+	if let Some(movie) = &mut movie {
+		movie.finish(&gb)?;
+	}
+	Ok(())
+}
+
+fn save_battery(
+	gb: &GB,
+	options: &cli::Options,
+	config: &config::Config,
+) -> Result<(), Box<dyn Error>> {
+	if !gb.bus.cart.battery {
+		return Ok(());
+	}
+	let path = save_path(options, config);
+	if let Some(dir) = path.parent() {
+		std::fs::create_dir_all(dir)?;
+	}
+	std::fs::write(path, gb.bus.cart.save_ram())?;
 	Ok(())
 }
+
+// test <rom>: true if the test ROM reported success
+fn test_command(options: &cli::Options, config: &config::Config) -> Result<bool, Box<dyn Error>> {
+	const DEFAULT_FRAMES: u64 = 60 * 60;
+	// mooneye test ROMs run LD B,B with these in B, C, D, E, H and L
+	const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+	const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+	let mut gb = load(options, config)?;
+	gb.bus.io.serial_out = Some(vec![]);
+	let mut serial_len = 0;
+	let frames = options.frames.unwrap_or(DEFAULT_FRAMES);
+	let mut dots = 0;
+	while dots < frames * DOTS_PER_FRAME {
+		if !gb.cpu.halt && gb.bus.peek_unlogged(gb.cpu.pc) == 0x40 {
+			let cpu = &gb.cpu;
+			let regs = [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l];
+			if regs == MOONEYE_PASS {
+				println!("Passed");
+				return Ok(true);
+			}
+			if regs == MOONEYE_FAIL {
+				println!("Failed");
+				return Ok(false);
+			}
+		}
+
+		let mcycles = cpu::cycle(&mut gb);
+		dots += mcycles * 4;
+
+		// blargg test ROMs print their results over serial
+		let serial_out = gb.bus.io.serial_out.as_deref().unwrap_or_default();
+		if serial_out.len() != serial_len {
+			serial_len = serial_out.len();
+			let serial = String::from_utf8_lossy(serial_out);
+			if serial.contains("Passed") || serial.contains("Failed") {
+				println!("{}", serial.trim_end());
+				return Ok(serial.contains("Passed"));
+			}
+		}
+		if let Some(pc) = gb.cpu.locked {
+			println!("CPU locked up at {pc:04x}");
+			return Ok(false);
+		}
+	}
+	let serial_out = gb.bus.io.serial_out.as_deref().unwrap_or_default();
+	print!("{}", String::from_utf8_lossy(serial_out));
+	println!("Timed out after {frames} frames");
+	Ok(false)
+}
@@ -0,0 +1,45 @@
+// Game Boy hardware revisions that behave differently enough to matter.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Model {
+	#[default]
+	DMG,
+	MGB, // Game Boy Pocket
+	SGB,
+	CGB,
+	AGB, // Game Boy Advance in GB mode
+}
+impl Model {
+	pub fn from_name(name: &str) -> Option<Model> {
+		match name.to_ascii_lowercase().as_str() {
+			"dmg" => Some(Model::DMG),
+			"mgb" => Some(Model::MGB),
+			"sgb" => Some(Model::SGB),
+			"cgb" => Some(Model::CGB),
+			"agb" => Some(Model::AGB),
+			_ => None,
+		}
+	}
+	/// Pick the most capable model the cartridge asks for.
+	pub fn from_header(header: &[u8]) -> Model {
+		if header[0x143] & 0x80 != 0 {
+			Model::CGB
+		} else if header[0x146] == 0x03 {
+			Model::SGB
+		} else {
+			Model::DMG
+		}
+	}
+	pub fn is_cgb(&self) -> bool {
+		matches!(self, Model::CGB | Model::AGB)
+	}
+	pub fn is_sgb(&self) -> bool {
+		matches!(self, Model::SGB)
+	}
+	pub fn boot_rom_size(&self) -> usize {
+		match self.is_cgb() {
+			true => 0x900,
+			false => 0x100,
+		}
+	}
+}
@@ -0,0 +1,401 @@
+use crate::GB;
+use crate::model::Model;
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Input movies: the joypad state for each frame, so a session can be played
+// back exactly. Frames are counted at VBlank and input only changes between
+// them, so recording holds back presses until the next frame starts too.
+// A hash of the system state is written every HASH_INTERVAL frames and checked
+// on playback to catch desyncs.
+//
+//   version 1
+//   rom 8f3c0e7d5b2a1964
+//   model DMG
+//   boot none
+//   start power-on
+//   input 0 00 00
+//   hash 60 2c4bd0e3a1f58e07
+//   input 75 08 00   (frame, joypad, buttons, as in user_input_*)
+//   end 1234
+//
+// Known limitation: there are no save states, so a movie can't start from an
+// embedded one. Movies start from power-on, with empty cartridge RAM, and any
+// other `start` line is rejected.
+// TODO: `start state <data>` once save states exist
+
+const VERSION: u32 = 1;
+const HASH_INTERVAL: u64 = 60;
+
+struct Input {
+	frame: u64,
+	joypad: u8,
+	buttons: u8,
+}
+
+enum Mode {
+	Record(BufWriter<File>),
+	Play {
+		inputs: Vec<Input>,
+		hashes: Vec<(u64, u64)>,
+		end: u64,
+		// the next input and hash not yet reached
+		next_input: usize,
+		next_hash: usize,
+	},
+}
+
+pub struct Movie {
+	mode: Mode,
+	frame: Option<u64>, // the last frame seen
+	joypad: u8,
+	buttons: u8,
+	pub finished: bool,
+}
+impl Movie {
+	pub fn record(path: &str, gb: &GB) -> Result<Movie, String> {
+		let file = File::create(path).map_err(|e| format!("{path}: {e}"))?;
+		let mut out = BufWriter::new(file);
+		let header = Header::of(gb);
+		writeln!(
+			out,
+			"version {VERSION}\nrom {:016x}\nmodel {:?}\nboot {}\nstart power-on",
+			header.rom,
+			header.model,
+			header.boot_name()
+		)
+		.map_err(|e| e.to_string())?;
+		info!(target: "tools", "Recording movie {path}");
+		Ok(Movie {
+			mode: Mode::Record(out),
+			frame: None,
+			joypad: 0,
+			buttons: 0,
+			finished: false,
+		})
+	}
+
+	pub fn play(path: &str, gb: &GB) -> Result<Movie, String> {
+		let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+		let MovieFile {
+			header,
+			inputs,
+			hashes,
+			end,
+		} = MovieFile::parse(&text).map_err(|e| format!("{path}:{e}"))?;
+		let expected = Header::of(gb);
+
+		if header.rom != expected.rom {
+			return Err(format!("{path} was recorded with a different ROM"));
+		}
+		if header.model != expected.model {
+			return Err(format!(
+				"{path} was recorded on {:?}, use --model {:?}",
+				header.model, header.model
+			));
+		}
+		if header.boot != expected.boot {
+			return Err(format!(
+				"{path} was recorded with boot ROM {}, not {}",
+				header.boot_name(),
+				expected.boot_name()
+			));
+		}
+		let end = match end {
+			Some(end) => end,
+			None => {
+				warn!(target: "tools", "{path} has no end, it was probably cut short");
+				inputs.last().map_or(0, |input| input.frame)
+			}
+		};
+		info!(target: "tools", "Playing movie {path}, {end} frames");
+		Ok(Movie {
+			mode: Mode::Play {
+				inputs,
+				hashes,
+				end,
+				next_input: 0,
+				next_hash: 0,
+			},
+			frame: None,
+			joypad: 0,
+			buttons: 0,
+			finished: false,
+		})
+	}
+
+	/// Run before emulating, after the frontend has set the input.
+	/// Recording latches the input at the start of each frame and holds it
+	/// until the next one; playback replaces it.
+	pub fn update(&mut self, gb: &mut GB) -> Result<(), String> {
+		let frame = gb.bus.frames;
+		let new_frame = self.frame != Some(frame);
+		self.frame = Some(frame);
+
+		match &mut self.mode {
+			Mode::Record(out) => {
+				if new_frame {
+					let io = &gb.bus.io;
+					let (joypad, buttons) = (io.user_input_joypad, io.user_input_buttons);
+					if frame == 0 || (joypad, buttons) != (self.joypad, self.buttons) {
+						writeln!(out, "input {frame} {joypad:02x} {buttons:02x}")
+							.map_err(|e| e.to_string())?;
+					}
+					(self.joypad, self.buttons) = (joypad, buttons);
+					if frame.is_multiple_of(HASH_INTERVAL) {
+						writeln!(out, "hash {frame} {:016x}", state_hash(gb))
+							.map_err(|e| e.to_string())?;
+					}
+					return Ok(());
+				}
+			}
+			Mode::Play { .. } if self.finished => return Ok(()),
+			Mode::Play {
+				inputs,
+				hashes,
+				end,
+				next_input,
+				next_hash,
+			} => {
+				if frame >= *end {
+					info!(target: "tools", "Movie finished at frame {frame}");
+					self.finished = true;
+					return Ok(());
+				}
+				if new_frame {
+					// frames only go forward, so both lists are walked once
+					while let Some(input) = inputs.get(*next_input)
+						&& input.frame <= frame
+					{
+						(self.joypad, self.buttons) = (input.joypad, input.buttons);
+						*next_input += 1;
+					}
+					while hashes.get(*next_hash).is_some_and(|&(f, _)| f < frame) {
+						*next_hash += 1;
+					}
+					if let Some(&(f, expected)) = hashes.get(*next_hash)
+						&& f == frame
+					{
+						*next_hash += 1;
+						let hash = state_hash(gb);
+						if hash != expected {
+							return Err(format!(
+								"Movie desynced at frame {frame}: state hash {hash:016x}, recorded {expected:016x}"
+							));
+						}
+					}
+				}
+			}
+		}
+		let io = &mut gb.bus.io;
+		io.user_input_joypad = self.joypad;
+		io.user_input_buttons = self.buttons;
+		Ok(())
+	}
+
+	/// Mark where a recording stops
+	pub fn finish(&mut self, gb: &GB) -> Result<(), String> {
+		if let Mode::Record(out) = &mut self.mode {
+			writeln!(out, "end {}", gb.bus.frames).map_err(|e| e.to_string())?;
+			out.flush().map_err(|e| e.to_string())?;
+			info!(target: "tools", "Recorded {} frames", gb.bus.frames);
+		}
+		Ok(())
+	}
+}
+
+// The movie as written, before it is checked against the loaded ROM
+struct MovieFile {
+	header: Header,
+	inputs: Vec<Input>,
+	hashes: Vec<(u64, u64)>, // (frame, state hash)
+	end: Option<u64>,
+}
+impl MovieFile {
+	/// Errors start with the line number
+	fn parse(text: &str) -> Result<MovieFile, String> {
+		let mut header = Header::default();
+		let (mut inputs, mut hashes, mut end) = (Vec::<Input>::new(), vec![], None);
+		for (n, line) in text.lines().enumerate() {
+			let at = |e: &str| format!("{}: {e}", n + 1);
+			let words: Vec<&str> = line.split_whitespace().collect();
+			let number = |i: usize| -> Result<u64, String> {
+				words
+					.get(i)
+					.and_then(|w| w.parse().ok())
+					.ok_or(at("Bad number"))
+			};
+			let hex = |i: usize| -> Result<u64, String> {
+				let word = words.get(i).ok_or(at("Missing value"))?;
+				u64::from_str_radix(word, 16).map_err(|_| at("Bad hex number"))
+			};
+			match words.first().copied() {
+				None => {}
+				Some("version") if number(1)? == VERSION as u64 => {}
+				Some("version") => return Err(at("Unsupported movie version")),
+				Some("rom") => header.rom = hex(1)?,
+				Some("model") => {
+					let name = words.get(1).copied().unwrap_or_default();
+					header.model = Model::from_name(name).ok_or(at("Unknown model"))?;
+				}
+				Some("boot") => {
+					header.boot = match words.get(1).copied() {
+						Some("none") => None,
+						_ => Some(hex(1)?),
+					}
+				}
+				Some("start") if words.get(1) == Some(&"power-on") => {}
+				Some("start") => return Err(at("Movies can only start from power-on")),
+				Some("input") => {
+					let frame = number(1)?;
+					if inputs.last().is_some_and(|input| input.frame > frame) {
+						return Err(at("Inputs are out of frame order"));
+					}
+					inputs.push(Input {
+						frame,
+						joypad: hex(2)? as u8,
+						buttons: hex(3)? as u8,
+					})
+				}
+				Some("hash") => {
+					let frame = number(1)?;
+					if hashes.last().is_some_and(|&(f, _)| f > frame) {
+						return Err(at("Hashes are out of frame order"));
+					}
+					hashes.push((frame, hex(2)?))
+				}
+				Some("end") => end = Some(number(1)?),
+				Some(other) => return Err(at(&format!("Unknown line {other}"))),
+			}
+		}
+		Ok(MovieFile {
+			header,
+			inputs,
+			hashes,
+			end,
+		})
+	}
+}
+
+#[derive(Default)]
+struct Header {
+	rom: u64,
+	model: Model,
+	boot: Option<u64>,
+}
+impl Header {
+	fn of(gb: &GB) -> Header {
+		let boot_rom = &gb.bus.boot_rom;
+		Header {
+			rom: hash(gb.bus.cart.rom.iter().flatten().copied(), FNV_OFFSET),
+			model: gb.bus.io.model,
+			boot: match boot_rom.is_empty() {
+				true => None,
+				false => Some(hash(boot_rom.iter().copied(), FNV_OFFSET)),
+			},
+		}
+	}
+	fn boot_name(&self) -> String {
+		match self.boot {
+			Some(boot) => format!("{boot:016x}"),
+			None => "none".into(),
+		}
+	}
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// FNV-1a, continuing from a previous hash
+fn hash(bytes: impl IntoIterator<Item = u8>, seed: u64) -> u64 {
+	bytes
+		.into_iter()
+		.fold(seed, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Hash of the CPU registers and memory, to compare a playback against its recording
+fn state_hash(gb: &GB) -> u64 {
+	let cpu = &gb.cpu;
+	let bus = &gb.bus;
+	let regs = [
+		cpu.a,
+		cpu.f.bits(),
+		cpu.b,
+		cpu.c,
+		cpu.d,
+		cpu.e,
+		cpu.h,
+		cpu.l,
+		(cpu.sp >> 8) as u8,
+		cpu.sp as u8,
+		(cpu.pc >> 8) as u8,
+		cpu.pc as u8,
+	];
+	let mut h = hash(regs, FNV_OFFSET);
+	h = hash(bus.mcycles.to_le_bytes(), h);
+	h = hash(bus.vram.iter().copied(), h);
+	h = hash(bus.wram.iter().copied(), h);
+	h = hash(bus.oam.iter().copied(), h);
+	h = hash(bus.hram.iter().copied(), h);
+	hash(bus.cart.exram.iter().flatten().copied(), h)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MOVIE: &str = "\
+version 1
+rom 8f3c0e7d5b2a1964
+model DMG
+boot none
+start power-on
+input 0 00 00
+hash 60 2c4bd0e3a1f58e07
+input 75 08 00
+end 1234
+";
+
+	fn error_of(text: &str) -> String {
+		MovieFile::parse(text).err().expect("should fail")
+	}
+
+	#[test]
+	fn parse() {
+		let movie = MovieFile::parse(MOVIE).unwrap();
+		assert_eq!(movie.header.rom, 0x8f3c0e7d5b2a1964);
+		assert_eq!(movie.header.model, Model::DMG);
+		assert_eq!(movie.header.boot, None);
+		let inputs: Vec<(u64, u8, u8)> = movie
+			.inputs
+			.iter()
+			.map(|i| (i.frame, i.joypad, i.buttons))
+			.collect();
+		assert_eq!(inputs, [(0, 0, 0), (75, 8, 0)]);
+		assert_eq!(movie.hashes, [(60, 0x2c4bd0e3a1f58e07)]);
+		assert_eq!(movie.end, Some(1234));
+	}
+
+	#[test]
+	fn out_of_order() {
+		let inputs = "input 10 00 00\ninput 20 01 00\ninput 15 00 00\n";
+		assert_eq!(error_of(inputs), "3: Inputs are out of frame order");
+		let hashes = "hash 120 0\nhash 60 0\n";
+		assert_eq!(error_of(hashes), "2: Hashes are out of frame order");
+		// the same frame twice is fine
+		assert!(MovieFile::parse("input 10 00 00\ninput 10 01 00\n").is_ok());
+	}
+
+	#[test]
+	fn bad_lines() {
+		assert_eq!(error_of("version 2"), "1: Unsupported movie version");
+		assert_eq!(
+			error_of("start state"),
+			"1: Movies can only start from power-on"
+		);
+		assert_eq!(error_of("\nfps 60"), "2: Unknown line fps");
+		assert_eq!(error_of("input x 00 00"), "1: Bad number");
+		assert_eq!(error_of("input 1 0g 00"), "1: Bad hex number");
+		assert_eq!(error_of("input 1 00"), "1: Missing value");
+	}
+}
@@ -0,0 +1,111 @@
+use crate::GB;
+use crate::callstack::location;
+use crate::symbols::Symbols;
+use log::info;
+use std::collections::HashMap;
+use std::io::Write;
+
+// Attributes M-cycles to addresses and to the functions on the shadow call
+// stack. The collapsed stacks ("outer;inner count" per line) can be fed to
+// flamegraph tools such as inferno or flamegraph.pl.
+
+const REPORT_LINES: usize = 20;
+
+type Location = (usize, u16); // bank, address
+
+pub struct Profiler {
+	path: String,
+	total: u64,
+	by_addr: HashMap<Location, u64>,
+	by_function: HashMap<Option<Location>, u64>, // None outside any call
+	by_stack: HashMap<Vec<Location>, u64>,
+	// what runs in the current cycle
+	addr: Location,
+	stack: Vec<Location>,
+}
+impl Profiler {
+	pub fn new(path: &str) -> Profiler {
+		Profiler {
+			path: path.into(),
+			total: 0,
+			by_addr: HashMap::new(),
+			by_function: HashMap::new(),
+			by_stack: HashMap::new(),
+			addr: (0, 0),
+			stack: vec![],
+		}
+	}
+
+	/// Note where the CPU is before it runs a cycle
+	pub fn begin(&mut self, gb: &GB) {
+		self.addr = (gb.bus.bank_at(gb.cpu.pc), gb.cpu.pc);
+		self.stack.clear();
+		self.stack
+			.extend(gb.cpu.calls.frames.iter().map(|frame| frame.target));
+	}
+
+	/// Charge the cycle's M-cycles to the location from begin
+	pub fn end(&mut self, mcycles: u64) {
+		self.total += mcycles;
+		*self.by_addr.entry(self.addr).or_default() += mcycles;
+		*self
+			.by_function
+			.entry(self.stack.last().copied())
+			.or_default() += mcycles;
+		match self.by_stack.get_mut(self.stack.as_slice()) {
+			Some(count) => *count += mcycles,
+			None => {
+				self.by_stack.insert(self.stack.clone(), mcycles);
+			}
+		}
+	}
+
+	/// Print the hottest addresses and functions, and write the collapsed stacks
+	pub fn finish(&self, symbols: &Symbols) -> std::io::Result<()> {
+		println!("Profile: {} M-cycles", self.total);
+
+		println!("Hottest addresses:");
+		for (addr, count) in hottest(&self.by_addr) {
+			println!("{}  {}", self.share(count), location(symbols, addr));
+		}
+
+		println!("Hottest functions (self time):");
+		for (function, count) in hottest(&self.by_function) {
+			let function = match function {
+				Some(entry) => location(symbols, entry),
+				None => "(top level)".into(),
+			};
+			println!("{}  {function}", self.share(count));
+		}
+
+		let mut out = std::io::BufWriter::new(std::fs::File::create(&self.path)?);
+		for (stack, count) in &self.by_stack {
+			let mut names = vec!["(top level)".to_string()];
+			names.extend(stack.iter().map(|&l| frame_name(symbols, l)));
+			writeln!(out, "{} {count}", names.join(";"))?;
+		}
+		out.flush()?;
+		info!(target: "tools", "Wrote collapsed stacks to {}", self.path);
+		Ok(())
+	}
+
+	fn share(&self, count: u64) -> String {
+		let percent = count as f64 * 100.0 / self.total.max(1) as f64;
+		format!("{percent:6.2}% {count:>12}")
+	}
+}
+
+fn hottest<K: Copy>(counts: &HashMap<K, u64>) -> Vec<(K, u64)> {
+	let mut counts: Vec<(K, u64)> = counts.iter().map(|(&k, &v)| (k, v)).collect();
+	counts.sort_by_key(|count| std::cmp::Reverse(count.1));
+	counts.truncate(REPORT_LINES);
+	counts
+}
+
+// Collapsed stack frames are separated by ';' and end at the last space
+fn frame_name(symbols: &Symbols, (bank, addr): Location) -> String {
+	match symbols.describe(bank, addr) {
+		Some(label) => label,
+		None => format!("{bank:02x}:{addr:04x}"),
+	}
+}
@@ -0,0 +1,441 @@
+use crate::video;
+use log::warn;
+
+// Super Game Boy support.
+// Games talk to the SNES side by pulsing P14/P15 in the P1 register.
+// Each packet is 16 bytes sent LSB first, preceded by a reset pulse
+// (both lines low) and followed by a single 0 stop bit.
+
+pub const BORDER_WIDTH: usize = 256;
+pub const BORDER_HEIGHT: usize = 224;
+
+// Position of the game screen inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const TRANSFER_SIZE: usize = 0x1000;
+
+const ATTR_FILE_SIZE: usize = 20 * 18 / 4;
+const ATTR_FILES: usize = 45;
+
+#[derive(Clone, Copy, Debug)]
+enum Transfer {
+	Palettes,
+	Tiles(usize),
+	Picture,
+	Attributes,
+}
+
+pub struct SGB {
+	pub enabled: bool,
+
+	// packet receiver
+	packet: [u8; PACKET_SIZE],
+	packet_bits: usize,
+	receiving: bool,
+	ready_for_pulse: bool,
+	command: Vec<u8>,
+
+	// SGB palettes 0-3, color 0 is shared between all of them
+	palettes: [[u16; 4]; 4],
+	system_palettes: Vec<[u16; 4]>,
+	attr_map: [u8; 20 * 18],
+	attr_files: Vec<[u8; ATTR_FILE_SIZE]>,
+	mask: u8,
+
+	border_tiles: Vec<u8>,
+	border_map: Vec<u16>,
+	border_palettes: [[u16; 16]; 4],
+
+	pending_transfer: Option<Transfer>,
+
+	pub players: u8,
+	pub current_player: u8,
+
+	// 2-bit shades of the last rendered frame, as sent to the SNES
+	pub screen: Box<[u8]>,
+	// Composited RGB output, including the border
+	pub output: Box<[u8]>,
+}
+impl std::default::Default for SGB {
+	fn default() -> SGB {
+		SGB {
+			enabled: false,
+
+			packet: [0; PACKET_SIZE],
+			packet_bits: 0,
+			receiving: false,
+			ready_for_pulse: false,
+			command: vec![],
+
+			palettes: [[0x7FFF, 0x56B5, 0x294A, 0x0000]; 4],
+			system_palettes: vec![[0; 4]; 512],
+			attr_map: [0; 20 * 18],
+			attr_files: vec![[0; ATTR_FILE_SIZE]; ATTR_FILES],
+			mask: 0,
+
+			border_tiles: vec![0; 256 * 32],
+			border_map: vec![0; 32 * 32],
+			border_palettes: [[0; 16]; 4],
+
+			pending_transfer: None,
+
+			players: 1,
+			current_player: 0,
+
+			screen: vec![0; 160 * 144].into_boxed_slice(),
+			output: vec![0; BORDER_WIDTH * BORDER_HEIGHT * 3].into_boxed_slice(),
+		}
+	}
+}
+impl SGB {
+	/// Cartridge header requests SGB functions.
+	pub fn supported_by(header: &[u8]) -> bool {
+		header[0x146] == 0x03 && header[0x14B] == 0x33
+	}
+
+	pub fn write_p1(&mut self, old: u8, data: u8) {
+		match data & 0b11_0000 {
+			0b00_0000 => {
+				// reset pulse, start of a packet
+				self.receiving = true;
+				self.ready_for_pulse = false;
+				self.packet = [0; PACKET_SIZE];
+				self.packet_bits = 0;
+			}
+			0b11_0000 => {
+				self.ready_for_pulse = true;
+
+				// joypad id advances when P15 goes high
+				if self.players > 1 && old & 0b10_0000 == 0 {
+					self.current_player = (self.current_player + 1) % self.players;
+				}
+			}
+			bit_pulse => {
+				if !self.receiving || !self.ready_for_pulse {
+					return;
+				}
+				self.ready_for_pulse = false;
+
+				// P15 low sends a 1, P14 low sends a 0
+				let bit = bit_pulse == 0b01_0000;
+				if self.packet_bits < PACKET_SIZE * 8 {
+					if bit {
+						self.packet[self.packet_bits / 8] |= 1 << (self.packet_bits % 8);
+					}
+					self.packet_bits += 1;
+				} else {
+					self.receiving = false;
+					if bit {
+						warn!(target: "sgb", "bad packet stop bit");
+					} else {
+						self.receive_packet();
+					}
+				}
+			}
+		}
+	}
+
+	fn receive_packet(&mut self) {
+		if self.command.is_empty() && self.packet[0] & 7 == 0 {
+			// packet count of 0 is not a valid command
+			return;
+		}
+		self.command.extend_from_slice(&self.packet);
+		let packets = (self.command[0] & 7) as usize;
+		if self.command.len() >= packets * PACKET_SIZE {
+			let command = std::mem::take(&mut self.command);
+			self.run_command(&command);
+		}
+	}
+
+	fn run_command(&mut self, data: &[u8]) {
+		match data[0] >> 3 {
+			0x00 => self.set_palette_pair(0, 1, data),
+			0x01 => self.set_palette_pair(2, 3, data),
+			0x02 => self.set_palette_pair(0, 3, data),
+			0x03 => self.set_palette_pair(1, 2, data),
+			0x04 => self.attr_blk(data),
+			0x05 => self.attr_lin(data),
+			0x06 => self.attr_div(data),
+			0x07 => self.attr_chr(data),
+			0x0A => {
+				// PAL_SET
+				for i in 0..4 {
+					let n = u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) as usize;
+					self.palettes[i] = self.system_palettes[n & 0x1ff];
+				}
+				let shared_color0 = self.palettes[0][0];
+				for palette in &mut self.palettes {
+					palette[0] = shared_color0;
+				}
+				if data[9] & 0x80 != 0 {
+					self.attr_set(data[9]);
+				}
+				if data[9] & 0x40 != 0 {
+					self.mask = 0;
+				}
+			}
+			0x0B => self.pending_transfer = Some(Transfer::Palettes),
+			0x11 => {
+				// MLT_REQ
+				self.players = match data[1] & 3 {
+					1 => 2,
+					3 => 4,
+					_ => 1,
+				};
+				self.current_player = 0;
+			}
+			0x13 => self.pending_transfer = Some(Transfer::Tiles((data[1] & 1) as usize)),
+			0x14 => self.pending_transfer = Some(Transfer::Picture),
+			0x15 => self.pending_transfer = Some(Transfer::Attributes),
+			0x16 => {
+				self.attr_set(data[1]);
+				if data[1] & 0x40 != 0 {
+					self.mask = 0;
+				}
+			}
+			0x17 => self.mask = data[1] & 3,
+			cmd => warn!(target: "sgb", "unsupported command {cmd:#04x}"),
+		}
+	}
+
+	fn set_palette_pair(&mut self, a: usize, b: usize, data: &[u8]) {
+		let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]);
+		for palette in &mut self.palettes {
+			palette[0] = color(0);
+		}
+		for i in 1..4 {
+			self.palettes[a][i] = color(i);
+			self.palettes[b][i] = color(i + 3);
+		}
+	}
+
+	fn attr_blk(&mut self, data: &[u8]) {
+		let sets = (data[1] & 0x1f) as usize;
+		for set in data[2..].chunks_exact(6).take(sets) {
+			let control = set[0] & 7;
+			let inside = set[1] & 3;
+			let border = (set[1] >> 2) & 3;
+			let outside = (set[1] >> 4) & 3;
+			// changing only the inside (or outside) also changes the border
+			let border = match control {
+				1 => Some(inside),
+				4 => Some(outside),
+				_ if control & 2 != 0 => Some(border),
+				_ => None,
+			};
+			let (x1, y1) = (set[2] as usize, set[3] as usize);
+			let (x2, y2) = (set[4] as usize, set[5] as usize);
+			for y in 0..18 {
+				for x in 0..20 {
+					let palette = if x > x1 && x < x2 && y > y1 && y < y2 {
+						(control & 1 != 0).then_some(inside)
+					} else if x < x1 || x > x2 || y < y1 || y > y2 {
+						(control & 4 != 0).then_some(outside)
+					} else {
+						border
+					};
+					if let Some(p) = palette {
+						self.attr_map[x + y * 20] = p;
+					}
+				}
+			}
+		}
+	}
+
+	fn attr_lin(&mut self, data: &[u8]) {
+		let sets = data[1] as usize;
+		for set in data[2..].iter().take(sets) {
+			let line = (set & 0x1f) as usize;
+			let palette = (set >> 5) & 3;
+			if set & 0x80 != 0 {
+				// horizontal line
+				for x in 0..20 {
+					if line < 18 {
+						self.attr_map[x + line * 20] = palette;
+					}
+				}
+			} else {
+				// vertical line
+				for y in 0..18 {
+					if line < 20 {
+						self.attr_map[line + y * 20] = palette;
+					}
+				}
+			}
+		}
+	}
+
+	fn attr_div(&mut self, data: &[u8]) {
+		let after = data[1] & 3;
+		let before = (data[1] >> 2) & 3;
+		let on_line = (data[1] >> 4) & 3;
+		let horizontal = data[1] & 0x40 != 0;
+		let line = data[2] as usize;
+		for y in 0..18 {
+			for x in 0..20 {
+				let pos = if horizontal { y } else { x };
+				self.attr_map[x + y * 20] = match pos.cmp(&line) {
+					std::cmp::Ordering::Less => before,
+					std::cmp::Ordering::Equal => on_line,
+					std::cmp::Ordering::Greater => after,
+				};
+			}
+		}
+	}
+
+	fn attr_chr(&mut self, data: &[u8]) {
+		let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+		let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(20 * 18);
+		let vertical = data[5] & 1 != 0;
+		for i in 0..count {
+			let Some(byte) = data.get(6 + i / 4) else {
+				break;
+			};
+			if x < 20 && y < 18 {
+				self.attr_map[x + y * 20] = (byte >> (6 - 2 * (i % 4))) & 3;
+			}
+			if vertical {
+				y += 1;
+				if y >= 18 {
+					y = 0;
+					x += 1;
+				}
+			} else {
+				x += 1;
+				if x >= 20 {
+					x = 0;
+					y += 1;
+				}
+			}
+		}
+	}
+
+	fn attr_set(&mut self, data: u8) {
+		let file = &self.attr_files[(data & 0x3f) as usize % ATTR_FILES];
+		for (i, attr) in self.attr_map.iter_mut().enumerate() {
+			*attr = (file[i / 4] >> (6 - 2 * (i % 4))) & 3;
+		}
+	}
+
+	// VRAM transfers send the tiles currently on screen, 20 per row.
+	fn screen_as_tiles(&self) -> Vec<u8> {
+		let mut out = Vec::with_capacity(TRANSFER_SIZE);
+		for itile in 0..TRANSFER_SIZE / 16 {
+			let (tile_x, tile_y) = ((itile % 20) * 8, (itile / 20) * 8);
+			for row in 0..8 {
+				let (mut lo, mut hi) = (0, 0);
+				for col in 0..8 {
+					let shade = self.screen[tile_x + col + (tile_y + row) * 160];
+					lo |= (shade & 1) << (7 - col);
+					hi |= ((shade >> 1) & 1) << (7 - col);
+				}
+				out.push(lo);
+				out.push(hi);
+			}
+		}
+		out
+	}
+
+	fn run_transfer(&mut self, transfer: Transfer) {
+		let data = self.screen_as_tiles();
+		let word = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+		match transfer {
+			Transfer::Palettes => {
+				for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+					for (c, color) in palette.iter_mut().enumerate() {
+						*color = word(i * 8 + c * 2);
+					}
+				}
+			}
+			Transfer::Tiles(half) => {
+				self.border_tiles[half * TRANSFER_SIZE..(half + 1) * TRANSFER_SIZE]
+					.copy_from_slice(&data);
+			}
+			Transfer::Picture => {
+				for (i, entry) in self.border_map.iter_mut().enumerate().take(32 * 28) {
+					*entry = word(i * 2);
+				}
+				for (p, palette) in self.border_palettes.iter_mut().enumerate() {
+					for (c, color) in palette.iter_mut().enumerate() {
+						*color = word(0x800 + p * 32 + c * 2);
+					}
+				}
+			}
+			Transfer::Attributes => {
+				for (i, file) in self.attr_files.iter_mut().enumerate() {
+					file.copy_from_slice(&data[i * ATTR_FILE_SIZE..(i + 1) * ATTR_FILE_SIZE]);
+				}
+			}
+		}
+	}
+
+	// 4bpp SNES tile color, 0 is transparent
+	fn border_color(&self, x: usize, y: usize) -> Option<u16> {
+		let entry = self.border_map[(x / 8) + (y / 8) * 32];
+		let itile = (entry & 0xff) as usize;
+		let palette = ((entry >> 10) & 3) as usize;
+		let px = if entry & 0x4000 != 0 {
+			x % 8
+		} else {
+			7 - x % 8
+		};
+		let py = if entry & 0x8000 != 0 {
+			7 - y % 8
+		} else {
+			y % 8
+		};
+
+		let tile = &self.border_tiles[itile * 32..itile * 32 + 32];
+		let n = ((tile[py * 2] >> px) & 1)
+			| (((tile[py * 2 + 1] >> px) & 1) << 1)
+			| (((tile[16 + py * 2] >> px) & 1) << 2)
+			| (((tile[16 + py * 2 + 1] >> px) & 1) << 3);
+		if n == 0 {
+			None
+		} else {
+			Some(self.border_palettes[palette][n as usize])
+		}
+	}
+
+	/// Finish a frame: run pending VRAM transfers and composite the output.
+	pub fn vblank(&mut self) {
+		if let Some(transfer) = self.pending_transfer.take() {
+			self.run_transfer(transfer);
+		}
+
+		let backdrop = self.palettes[0][0];
+		for y in 0..BORDER_HEIGHT {
+			for x in 0..BORDER_WIDTH {
+				let on_screen = (SCREEN_X..SCREEN_X + 160).contains(&x)
+					&& (SCREEN_Y..SCREEN_Y + 144).contains(&y);
+				let ofs = 3 * (x + y * BORDER_WIDTH);
+
+				let color = match self.border_color(x, y) {
+					Some(c) => c,
+					None if on_screen => {
+						let (lcd_x, lcd_y) = (x - SCREEN_X, y - SCREEN_Y);
+						match self.mask {
+							// frozen, keep the previous frame
+							1 => continue,
+							2 => 0,
+							3 => backdrop,
+							_ => {
+								let attr = self.attr_map[lcd_x / 8 + (lcd_y / 8) * 20];
+								let shade = self.screen[lcd_x + lcd_y * 160];
+								self.palettes[attr as usize][shade as usize]
+							}
+						}
+					}
+					None => backdrop,
+				};
+				let (r, g, b) = video::rgb555(color);
+				self.output[ofs] = r;
+				self.output[ofs + 1] = g;
+				self.output[ofs + 2] = b;
+			}
+		}
+	}
+}
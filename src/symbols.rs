@@ -0,0 +1,78 @@
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+// Symbols from an RGBDS .sym file, one "bank:addr label" per line:
+//   ; File generated by rgblink
+//   00:0150 Main
+//   01:4000 Main.loop
+
+#[derive(Default)]
+pub struct Symbols {
+	by_addr: BTreeMap<(usize, u16), String>,
+	by_name: HashMap<String, (usize, u16)>,
+}
+impl Symbols {
+	pub fn parse(text: &str) -> Symbols {
+		let mut symbols = Symbols::default();
+		for line in text.lines() {
+			let line = line.split(';').next().unwrap_or_default().trim();
+			let Some((location, name)) = line.split_once(char::is_whitespace) else {
+				continue;
+			};
+			let Some((bank, addr)) = location.split_once(':') else {
+				continue;
+			};
+			let (Ok(bank), Ok(addr)) = (
+				usize::from_str_radix(bank, 16),
+				u16::from_str_radix(addr, 16),
+			) else {
+				continue;
+			};
+			let name = name.trim().to_string();
+			symbols.by_name.insert(name.clone(), (bank, addr));
+			// keep the first label when several share an address
+			symbols.by_addr.entry((bank, addr)).or_insert(name);
+		}
+		symbols
+	}
+
+	/// Load the .sym file that RGBDS put next to the ROM, if there is one
+	pub fn load_for_rom(rom_path: &str) -> Option<Symbols> {
+		let text = std::fs::read_to_string(Path::new(rom_path).with_extension("sym")).ok()?;
+		let symbols = Symbols::parse(&text);
+		info!(target: "tools", "Loaded {} symbols", symbols.by_name.len());
+		Some(symbols)
+	}
+
+	/// Bank and address of a label
+	pub fn lookup(&self, name: &str) -> Option<(usize, u16)> {
+		self.by_name.get(name).copied()
+	}
+
+	/// Label exactly at an address
+	pub fn label(&self, bank: usize, addr: u16) -> Option<&str> {
+		self.by_addr.get(&(bank, addr)).map(String::as_str)
+	}
+
+	/// Closest label at or before an address in the same bank, as "label+ofs"
+	pub fn describe(&self, bank: usize, addr: u16) -> Option<String> {
+		let (&(label_bank, label_addr), name) = self.by_addr.range(..=(bank, addr)).next_back()?;
+		// don't reach back across banks or memory regions
+		if label_bank != bank || addr - label_addr >= 0x1000 || region(addr) != region(label_addr) {
+			return None;
+		}
+		Some(match addr - label_addr {
+			0 => name.clone(),
+			ofs => format!("{name}+{ofs:x}"),
+		})
+	}
+}
+
+fn region(addr: u16) -> u16 {
+	match addr {
+		0x0000..=0x3FFF => 0,
+		0x4000..=0x7FFF => 1,
+		_ => addr >> 12,
+	}
+}
@@ -0,0 +1,65 @@
+use crate::bus::Bus;
+use crate::cpu::CPU;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+
+// Execution trace in the format used by Gameboy Doctor:
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+
+pub struct Trace {
+	out: BufWriter<File>,
+	pub pc_range: Option<RangeInclusive<u16>>, // only log instructions in here
+	pub remaining: Option<u64>,                // stop after this many lines
+}
+impl Trace {
+	pub fn create(path: &str) -> std::io::Result<Trace> {
+		Ok(Trace {
+			out: BufWriter::new(File::create(path)?),
+			pc_range: None,
+			remaining: None,
+		})
+	}
+	/// Log the state before the instruction at PC runs
+	pub fn log(&mut self, cpu: &CPU, bus: &Bus) -> std::io::Result<()> {
+		if let Some(range) = &self.pc_range
+			&& !range.contains(&cpu.pc)
+		{
+			return Ok(());
+		}
+		match &mut self.remaining {
+			Some(0) => return Ok(()),
+			Some(n) => *n -= 1,
+			None => {}
+		}
+		writeln!(self.out, "{}", format_line(cpu, bus))?;
+		if self.remaining == Some(0) {
+			self.out.flush()?;
+		}
+		Ok(())
+	}
+	/// Write out buffered lines, for when the trace won't be dropped normally
+	pub fn flush(&mut self) -> std::io::Result<()> {
+		self.out.flush()
+	}
+}
+
+pub fn format_line(cpu: &CPU, bus: &Bus) -> String {
+	let pcmem: Vec<String> = (0..4)
+		.map(|i| format!("{:02X}", bus.peek_unlogged(cpu.pc.wrapping_add(i))))
+		.collect();
+	format!(
+		"A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+		cpu.a,
+		cpu.f.bits(),
+		cpu.b,
+		cpu.c,
+		cpu.d,
+		cpu.e,
+		cpu.h,
+		cpu.l,
+		cpu.sp,
+		cpu.pc,
+		pcmem.join(","),
+	)
+}
@@ -1,8 +1,11 @@
-use crate::{GB, bus};
+use crate::config::Config;
+use crate::debugger::{Debugger, Stop};
+use crate::{GB, bus, sgb, video};
+use log::info;
 use raylib::{error::LoadTextureError, prelude::*};
 use std::error::Error;
 
-const CONTROLS: &[(bool, u8, KeyboardKey)] = &[
+pub const CONTROLS: &[(bool, u8, KeyboardKey)] = &[
 	// (is_joypad, io_pin, keycode)
 	(false, 1, KeyboardKey::KEY_R),         // A
 	(false, 2, KeyboardKey::KEY_E),         // B
@@ -14,6 +17,104 @@ const CONTROLS: &[(bool, u8, KeyboardKey)] = &[
 	(true, 8, KeyboardKey::KEY_K),          // DOWN
 ];
 
+/// Keys by the names config files use, like "A", "5", "Enter" or "F1"
+pub fn key_from_name(name: &str) -> Option<KeyboardKey> {
+	use KeyboardKey::*;
+	const LETTERS: [KeyboardKey; 26] = [
+		KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G, KEY_H, KEY_I, KEY_J, KEY_K, KEY_L, KEY_M,
+		KEY_N, KEY_O, KEY_P, KEY_Q, KEY_R, KEY_S, KEY_T, KEY_U, KEY_V, KEY_W, KEY_X, KEY_Y, KEY_Z,
+	];
+	const DIGITS: [KeyboardKey; 10] = [
+		KEY_ZERO, KEY_ONE, KEY_TWO, KEY_THREE, KEY_FOUR, KEY_FIVE, KEY_SIX, KEY_SEVEN, KEY_EIGHT,
+		KEY_NINE,
+	];
+	const FUNCTION: [KeyboardKey; 12] = [
+		KEY_F1, KEY_F2, KEY_F3, KEY_F4, KEY_F5, KEY_F6, KEY_F7, KEY_F8, KEY_F9, KEY_F10, KEY_F11,
+		KEY_F12,
+	];
+	let name = name.to_ascii_lowercase();
+	let mut chars = name.chars();
+	if let (Some(c), None) = (chars.next(), chars.next()) {
+		match c {
+			'a'..='z' => return Some(LETTERS[c as usize - 'a' as usize]),
+			'0'..='9' => return Some(DIGITS[c as usize - '0' as usize]),
+			_ => {}
+		}
+	}
+	if let Some(n) = name.strip_prefix('f')
+		&& let Ok(n @ 1..=12) = n.parse::<usize>()
+	{
+		return Some(FUNCTION[n - 1]);
+	}
+	Some(match name.as_str() {
+		"up" => KEY_UP,
+		"down" => KEY_DOWN,
+		"left" => KEY_LEFT,
+		"right" => KEY_RIGHT,
+		"enter" => KEY_ENTER,
+		"backspace" => KEY_BACKSPACE,
+		"space" => KEY_SPACE,
+		"tab" => KEY_TAB,
+		"leftshift" => KEY_LEFT_SHIFT,
+		"rightshift" => KEY_RIGHT_SHIFT,
+		"period" | "." => KEY_PERIOD,
+		"comma" | "," => KEY_COMMA,
+		"slash" | "/" => KEY_SLASH,
+		"minus" | "-" => KEY_MINUS,
+		"equal" | "=" => KEY_EQUAL,
+		_ => return None,
+	})
+}
+
+pub const GAMEPAD_CONTROLS: &[(bool, u8, GamepadButton)] = &[
+	// (is_joypad, io_pin, button), with A and B where they sit on a Game Boy
+	(false, 1, GamepadButton::GAMEPAD_BUTTON_RIGHT_FACE_RIGHT),
+	(false, 2, GamepadButton::GAMEPAD_BUTTON_RIGHT_FACE_DOWN),
+	(false, 4, GamepadButton::GAMEPAD_BUTTON_MIDDLE_LEFT),
+	(false, 8, GamepadButton::GAMEPAD_BUTTON_MIDDLE_RIGHT),
+	(true, 1, GamepadButton::GAMEPAD_BUTTON_LEFT_FACE_RIGHT),
+	(true, 2, GamepadButton::GAMEPAD_BUTTON_LEFT_FACE_LEFT),
+	(true, 4, GamepadButton::GAMEPAD_BUTTON_LEFT_FACE_UP),
+	(true, 8, GamepadButton::GAMEPAD_BUTTON_LEFT_FACE_DOWN),
+];
+
+/// Gamepad buttons by position, like "south" for A on an Xbox pad
+pub fn gamepad_button_from_name(name: &str) -> Option<GamepadButton> {
+	use GamepadButton::*;
+	Some(match name.to_ascii_lowercase().as_str() {
+		"dpad_up" => GAMEPAD_BUTTON_LEFT_FACE_UP,
+		"dpad_right" => GAMEPAD_BUTTON_LEFT_FACE_RIGHT,
+		"dpad_down" => GAMEPAD_BUTTON_LEFT_FACE_DOWN,
+		"dpad_left" => GAMEPAD_BUTTON_LEFT_FACE_LEFT,
+		"north" => GAMEPAD_BUTTON_RIGHT_FACE_UP,
+		"east" => GAMEPAD_BUTTON_RIGHT_FACE_RIGHT,
+		"south" => GAMEPAD_BUTTON_RIGHT_FACE_DOWN,
+		"west" => GAMEPAD_BUTTON_RIGHT_FACE_LEFT,
+		"l1" => GAMEPAD_BUTTON_LEFT_TRIGGER_1,
+		"l2" => GAMEPAD_BUTTON_LEFT_TRIGGER_2,
+		"r1" => GAMEPAD_BUTTON_RIGHT_TRIGGER_1,
+		"r2" => GAMEPAD_BUTTON_RIGHT_TRIGGER_2,
+		"back" | "select" => GAMEPAD_BUTTON_MIDDLE_LEFT,
+		"guide" => GAMEPAD_BUTTON_MIDDLE,
+		"start" => GAMEPAD_BUTTON_MIDDLE_RIGHT,
+		"l3" => GAMEPAD_BUTTON_LEFT_THUMB,
+		"r3" => GAMEPAD_BUTTON_RIGHT_THUMB,
+		_ => return None,
+	})
+}
+const MAX_GAMEPADS: i32 = 4;
+// Rumble is renewed every frame, so this only matters if frames stop coming
+const RUMBLE_SECONDS: f32 = 0.1;
+
+const KEY_CYCLE_PALETTE: KeyboardKey = KeyboardKey::KEY_P;
+
+// Debugger controls
+const KEY_PAUSE: KeyboardKey = KeyboardKey::KEY_F5;
+const KEY_STEP_INTO: KeyboardKey = KeyboardKey::KEY_F6;
+const KEY_STEP_OVER: KeyboardKey = KeyboardKey::KEY_F7;
+const KEY_STEP_OUT: KeyboardKey = KeyboardKey::KEY_F8;
+const KEY_RUN_TO_FRAME: KeyboardKey = KeyboardKey::KEY_F9;
+
 const VRAM_WIDTH: i32 = 32;
 const VRAM_HEIGHT: i32 = bus::VRAM_SIZE as i32 / VRAM_WIDTH;
 
@@ -56,12 +157,6 @@ impl Layout {
 	}
 }
 
-fn color_dmg(n: u8, palette: u8) -> (u8, u8, u8) {
-	let pcolor = (palette >> (n * 2)) & 0b11;
-	let c = 0x55 * (3 - pcolor);
-	(c, c, c)
-}
-
 fn blank_tex(
 	rl: &mut (RaylibHandle, RaylibThread),
 	w: i32,
@@ -86,16 +181,37 @@ pub struct UI {
 	tex: GbTextures,
 	frame_number: u64,
 	verbose: bool,
+	sgb_border: bool,
+	palette_preset: usize,
+	scale: i32,
+	controls: Vec<(bool, u8, KeyboardKey)>,
+	gamepad_controls: Vec<(bool, u8, GamepadButton)>,
+	gamepad: Option<i32>,
+	deadzone: f32,
+	rumble: f32,
+	rumbling: bool,
 }
 impl UI {
-	pub fn new(verbose: bool) -> Result<UI, LoadTextureError> {
+	pub fn new(
+		verbose: bool,
+		sgb_border: bool,
+		scale: i32,
+		config: &Config,
+	) -> Result<UI, LoadTextureError> {
+		let (screen_w, screen_h) = match sgb_border {
+			true => (sgb::BORDER_WIDTH as i32, sgb::BORDER_HEIGHT as i32),
+			false => (160, 144),
+		};
 		let (w, h) = match verbose {
 			true => (1920, 1080),
-			false => (160 * 3 + PADDING * 2, 144 * 3 + (PADDING * 2)),
+			false => (
+				screen_w * scale + PADDING * 2,
+				screen_h * scale + (PADDING * 2),
+			),
 		};
 		let mut rl = raylib::init().size(w, h).build();
 		let tex = GbTextures {
-			fb: blank_tex(&mut rl, 160, 144)?,
+			fb: blank_tex(&mut rl, screen_w, screen_h)?,
 			mem: blank_tex(&mut rl, 256, 256)?,
 			bg: blank_tex(&mut rl, 256, 256)?,
 			win: blank_tex(&mut rl, 256, 256)?,
@@ -107,27 +223,151 @@ impl UI {
 			tex,
 			frame_number: 0,
 			verbose,
+			sgb_border,
+			palette_preset: config.palette,
+			scale,
+			controls: config.keys.clone(),
+			gamepad_controls: config.gamepad.clone(),
+			gamepad: None,
+			deadzone: config.deadzone,
+			rumble: config.rumble,
+			rumbling: false,
 		})
 	}
-	pub fn draw(&mut self, gb: &mut GB, play: &mut bool) -> Result<(), Box<dyn Error>> {
+
+	/// Pick up the first gamepad plugged in, and notice when it goes away
+	fn find_gamepad(&mut self) {
+		if let Some(pad) = self.gamepad
+			&& !self.rl.0.is_gamepad_available(pad)
+		{
+			info!(target: "ui", "Gamepad {pad} disconnected");
+			self.gamepad = None;
+			self.rumbling = false;
+		}
+		if self.gamepad.is_none() {
+			self.gamepad = (0..MAX_GAMEPADS).find(|&pad| self.rl.0.is_gamepad_available(pad));
+			if let Some(pad) = self.gamepad {
+				let name = self.rl.0.get_gamepad_name(pad).unwrap_or_default();
+				info!(target: "ui", "Gamepad {pad} connected: {name}");
+			}
+		}
+	}
+
+	fn poll_input(&mut self, gb: &mut GB) {
+		let (mut joypad, mut buttons) = (0, 0);
+		let mut press = |is_joypad: bool, io_pin: u8| match is_joypad {
+			true => joypad |= io_pin,
+			false => buttons |= io_pin,
+		};
+		for &(is_joypad, io_pin, keycode) in &self.controls {
+			if self.rl.0.is_key_down(keycode) {
+				press(is_joypad, io_pin);
+			}
+		}
+
+		self.find_gamepad();
+		if let Some(pad) = self.gamepad {
+			for &(is_joypad, io_pin, button) in &self.gamepad_controls {
+				if self.rl.0.is_gamepad_button_down(pad, button) {
+					press(is_joypad, io_pin);
+				}
+			}
+			// the left stick works as a D-pad, with down as positive Y
+			let x = self
+				.rl
+				.0
+				.get_gamepad_axis_movement(pad, GamepadAxis::GAMEPAD_AXIS_LEFT_X);
+			let y = self
+				.rl
+				.0
+				.get_gamepad_axis_movement(pad, GamepadAxis::GAMEPAD_AXIS_LEFT_Y);
+			if x > self.deadzone {
+				press(true, 1);
+			}
+			if x < -self.deadzone {
+				press(true, 2);
+			}
+			if y < -self.deadzone {
+				press(true, 4);
+			}
+			if y > self.deadzone {
+				press(true, 8);
+			}
+		}
+		gb.bus.io.user_input_joypad = joypad;
+		gb.bus.io.user_input_buttons = buttons;
+	}
+
+	/// Run the gamepad motor while a rumble cartridge has its motor on
+	fn update_rumble(&mut self, gb: &mut GB) {
+		let cart = &mut gb.bus.cart;
+		let on = cart.rumble || cart.rumble_seen;
+		cart.rumble_seen = false;
+		let Some(pad) = self.gamepad else {
+			return;
+		};
+		if !cart.has_rumble || self.rumble == 0.0 || !(on || self.rumbling) {
+			return;
+		}
+		let (strength, seconds) = match on {
+			true => (self.rumble, RUMBLE_SECONDS),
+			false => (0.0, 0.0),
+		};
+		// raylib 5.5 has this, but the safe bindings don't wrap it
+		// SAFETY: only plain values cross over, no pointers. raylib ignores
+		// gamepad numbers it doesn't know, and this runs on the main thread
+		// after the window (and with it the input system) is set up.
+		unsafe { raylib::ffi::SetGamepadVibration(pad, strength, strength, seconds) };
+		self.rumbling = on;
+	}
+	pub fn draw(
+		&mut self,
+		gb: &mut GB,
+		debugger: &mut Debugger,
+		play: &mut bool,
+	) -> Result<(), Box<dyn Error>> {
 		if self.rl.0.window_should_close() {
 			*play = false
 		}
 
-		for (is_joypad, io_pin, keycode) in CONTROLS {
-			let target = if *is_joypad {
-				&mut gb.bus.io.user_input_joypad
-			} else {
-				&mut gb.bus.io.user_input_buttons
-			};
-			if self.rl.0.is_key_down(*keycode) {
-				*target |= io_pin
+		self.poll_input(gb);
+		self.update_rumble(gb);
+
+		if self.rl.0.is_key_pressed(KEY_PAUSE) {
+			if debugger.paused {
+				debugger.resume();
 			} else {
-				*target &= !io_pin
+				debugger.pause();
+				println!("{}", debugger.describe(&Stop::Pause, gb));
 			}
 		}
+		if debugger.paused {
+			if self.rl.0.is_key_pressed(KEY_STEP_INTO) {
+				debugger.step_into();
+			}
+			if self.rl.0.is_key_pressed(KEY_STEP_OVER) {
+				debugger.step_over(gb);
+			}
+			if self.rl.0.is_key_pressed(KEY_STEP_OUT) {
+				debugger.step_out(gb);
+			}
+			if self.rl.0.is_key_pressed(KEY_RUN_TO_FRAME) {
+				debugger.run_to_frame();
+			}
+		}
+
+		if self.rl.0.is_key_pressed(KEY_CYCLE_PALETTE) {
+			self.palette_preset = (self.palette_preset + 1) % video::PRESETS.len();
+			let (name, palettes) = video::PRESETS[self.palette_preset];
+			info!(target: "ui", "Palette: {name}");
+			gb.bus.io.dmg_palettes = palettes;
+		}
 
-		self.tex.fb.update_texture(&gb.framebuffer)?;
+		if self.sgb_border {
+			self.tex.fb.update_texture(&gb.bus.io.sgb.output)?;
+		} else {
+			self.tex.fb.update_texture(&gb.bus.framebuffer)?;
+		}
 		if self.verbose {
 			self.tex.mem.update_texture(&mem_dump(&gb.bus))?;
 			self.tex.bg.update_texture(&bg_map(&gb.bus))?;
@@ -162,13 +402,25 @@ impl UI {
 				Color::WHITE,
 			);
 		}
-		d.draw_texture_ex(
-			&self.tex.fb,
-			l.stack(self.tex.fb.width, self.tex.fb.height, 3),
-			0.0,
-			3.0,
-			Color::WHITE,
-		);
+		let fb_pos = l.stack(self.tex.fb.width, self.tex.fb.height, self.scale);
+		d.draw_texture_ex(&self.tex.fb, fb_pos, 0.0, self.scale as f32, Color::WHITE);
+		if let Some(pc) = gb.cpu.locked {
+			d.draw_text(
+				&format!("CPU locked up at ${pc:04X}"),
+				fb_pos.x as i32 + 8,
+				fb_pos.y as i32 + 8,
+				20,
+				Color::RED,
+			);
+		} else if debugger.paused {
+			d.draw_text(
+				&format!("Paused at ${:04X}", gb.cpu.pc),
+				fb_pos.x as i32 + 8,
+				fb_pos.y as i32 + 8,
+				20,
+				Color::RED,
+			);
+		}
 		if self.verbose {
 			d.draw_texture_ex(
 				&self.tex.tile,
@@ -190,25 +442,32 @@ impl UI {
 	}
 }
 
+// An RGB image being drawn into, and the pixel a tile's top left goes at
+struct TileTarget<'a> {
+	img: &'a mut [u8],
+	ofs: usize,
+	width: usize,
+}
+
 fn draw_tile(
 	itile: usize,
-	img: &mut [u8],
-	ofs: usize,
-	output_width: usize,
+	target: TileTarget,
 	bank: &[u8],
 	palette: u8,
+	shades: &video::Shades,
 	transparent: bool,
 ) {
+	let TileTarget { img, ofs, width } = target;
 	let data = &bank[itile * 16..itile * 16 + 16];
 	for x in 0..8 {
 		for y in 0..8 {
 			let b1 = (data[y * 2 + 0] >> (7 - x)) & 1;
 			let b2 = (data[y * 2 + 1] >> (7 - x)) & 1;
 			if b1 | b2 != 0 || !transparent {
-				let (r, g, b) = color_dmg(b1 | (b2 << 1), palette);
-				img[0 + 3 * (ofs + x + output_width * y)] = r;
-				img[1 + 3 * (ofs + x + output_width * y)] = g;
-				img[2 + 3 * (ofs + x + output_width * y)] = b;
+				let (r, g, b) = video::color_dmg(b1 | (b2 << 1), palette, shades);
+				img[0 + 3 * (ofs + x + width * y)] = r;
+				img[1 + 3 * (ofs + x + width * y)] = g;
+				img[2 + 3 * (ofs + x + width * y)] = b;
 			}
 		}
 	}
@@ -226,11 +485,14 @@ fn window_map(mem: &crate::bus::Bus) -> Box<[u8]> {
 			}
 			draw_tile(
 				itile,
-				img.as_mut(),
-				(x * 8) + (y * 8 * 256),
-				256,
+				TileTarget {
+					img: img.as_mut(),
+					ofs: (x * 8) + (y * 8 * 256),
+					width: 256,
+				},
 				&mem.vram,
 				mem.io.bgp,
+				&mem.io.dmg_palettes.bg,
 				false,
 			);
 		}
@@ -251,11 +513,14 @@ fn bg_map(mem: &crate::bus::Bus) -> Box<[u8]> {
 			}
 			draw_tile(
 				itile,
-				img.as_mut(),
-				(x * 8) + (y * 8 * 256),
-				256,
+				TileTarget {
+					img: img.as_mut(),
+					ofs: (x * 8) + (y * 8 * 256),
+					width: 256,
+				},
 				&mem.vram,
 				mem.io.bgp,
+				&mem.io.dmg_palettes.bg,
 				false,
 			);
 		}
@@ -272,12 +537,15 @@ fn tile_dump(mem: &crate::bus::Bus) -> Box<[u8]> {
 	for itile in 0..384 {
 		draw_tile(
 			itile,
-			img.as_mut(),
-			(itile % OUTPUT_WIDTH_IN_TILES as usize * 8)
-				+ (itile / OUTPUT_WIDTH_IN_TILES as usize * 8 * 8 * 16),
-			TILE_VIEWER_WIDTH as usize,
+			TileTarget {
+				img: img.as_mut(),
+				ofs: (itile % OUTPUT_WIDTH_IN_TILES as usize * 8)
+					+ (itile / OUTPUT_WIDTH_IN_TILES as usize * 8 * 8 * 16),
+				width: TILE_VIEWER_WIDTH as usize,
+			},
 			&mem.vram,
 			0b_11_10_01_00,
+			&mem.io.dmg_palettes.bg,
 			false,
 		);
 	}
@@ -305,7 +573,7 @@ fn mem_dump(mem: &crate::bus::Bus) -> Box<[u8]> {
 			0xE000..=0xFDFF => 1, // Echo Ram
 			0xFF00..=0xFF7F => 1, // IO Regs
 			0xFFFF => 1,          // IE Reg (skip to avoid verbose io log)
-			_ => mem.peek(i),
+			_ => mem.peek_unlogged(i),
 		}
 		.reverse_bits();
 		img[3 * (i as usize) + 0] = byte;
@@ -1,4 +1,6 @@
-use crate::GB;
+use crate::bus::Bus;
+use crate::ioreg::{INT_LCD, INT_VBLANK, IoReg};
+use log::trace;
 
 pub struct Sprite {
 	y: usize,
@@ -23,21 +25,54 @@ impl Sprite {
 	}
 }
 
-pub fn oam_scan(gb: &GB) -> Vec<Sprite> {
-	let sprite_h = match gb.bus.io.lcdc & 0b100 {
+const DOTS_PER_SCANLINE: u64 = 456;
+
+/// Advance the LCD by one dot
+pub fn tick_dot(bus: &mut Bus) {
+	if bus.io.lcdc & 0x80 == 0 {
+		return;
+	}
+	if bus.io.lx >= DOTS_PER_SCANLINE {
+		bus.io.lx = 0;
+		bus.io.ly += 1;
+		if bus.io.ly >= 154 {
+			bus.io.ly = 0;
+		}
+
+		if bus.io.ly == bus.io.lyc {
+			bus.io.interrupt |= INT_LCD;
+		}
+
+		if bus.io.ly == 144 {
+			bus.io.interrupt |= INT_VBLANK;
+			if bus.io.sgb.enabled {
+				bus.io.sgb.vblank();
+			}
+			bus.frame_done = true;
+			bus.frames += 1;
+			trace!(target: "ppu", "vblank, frame {}", bus.frames);
+		}
+	}
+	if bus.io.lx == 80 {
+		bus.sprites = oam_scan(bus);
+	}
+	render_dot(bus, bus.io.lx);
+	bus.io.lx += 1;
+}
+
+pub fn oam_scan(bus: &Bus) -> Vec<Sprite> {
+	let sprite_h = match bus.io.lcdc & 0b100 {
 		0 => 8,
 		_ => 16,
 	};
 	let mut sprites = vec![];
 	for oam_ofs in (0..(40 * 4)).step_by(4) {
-		if gb.bus.oam[oam_ofs] <= gb.bus.io.ly + 16
-			&& gb.bus.oam[oam_ofs] + sprite_h > gb.bus.io.ly + 16
-		{
+		if bus.oam[oam_ofs] <= bus.io.ly + 16 && bus.oam[oam_ofs] + sprite_h > bus.io.ly + 16 {
 			sprites.push(Sprite::new((
-				gb.bus.oam[oam_ofs + 0],
-				gb.bus.oam[oam_ofs + 1],
-				gb.bus.oam[oam_ofs + 2],
-				gb.bus.oam[oam_ofs + 3],
+				bus.oam[oam_ofs + 0],
+				bus.oam[oam_ofs + 1],
+				bus.oam[oam_ofs + 2],
+				bus.oam[oam_ofs + 3],
 			)));
 			if sprites.len() == 10 {
 				break;
@@ -48,96 +83,195 @@ pub fn oam_scan(gb: &GB) -> Vec<Sprite> {
 	sprites
 }
 
-pub fn color_dmg(n: u8, palette: u8) -> (u8, u8, u8) {
+// RGB output for each of the 4 DMG shades, lightest first
+pub type Shades = [(u8, u8, u8); 4];
+
+#[derive(Clone, Copy)]
+pub struct DmgPalettes {
+	pub bg: Shades,
+	pub obj0: Shades,
+	pub obj1: Shades,
+}
+impl DmgPalettes {
+	const fn uniform(shades: Shades) -> DmgPalettes {
+		DmgPalettes {
+			bg: shades,
+			obj0: shades,
+			obj1: shades,
+		}
+	}
+}
+impl std::default::Default for DmgPalettes {
+	fn default() -> DmgPalettes {
+		PRESETS[0].1
+	}
+}
+
+const GRAYSCALE: Shades = [
+	(0xff, 0xff, 0xff),
+	(0xaa, 0xaa, 0xaa),
+	(0x55, 0x55, 0x55),
+	(0, 0, 0),
+];
+
+pub const PRESETS: &[(&str, DmgPalettes)] = &[
+	("grayscale", DmgPalettes::uniform(GRAYSCALE)),
+	(
+		"classic",
+		DmgPalettes::uniform([
+			(0x9b, 0xbc, 0x0f),
+			(0x8b, 0xac, 0x0f),
+			(0x30, 0x62, 0x30),
+			(0x0f, 0x38, 0x0f),
+		]),
+	),
+	(
+		"pocket",
+		DmgPalettes::uniform([
+			(0xc4, 0xcf, 0xa1),
+			(0x8b, 0x95, 0x6d),
+			(0x4d, 0x53, 0x3c),
+			(0x1f, 0x1f, 0x1f),
+		]),
+	),
+	(
+		"light",
+		DmgPalettes::uniform([
+			(0x00, 0xb5, 0x81),
+			(0x00, 0x9a, 0x71),
+			(0x00, 0x69, 0x4a),
+			(0x00, 0x4f, 0x3b),
+		]),
+	),
+	(
+		"high-contrast",
+		DmgPalettes {
+			bg: GRAYSCALE,
+			obj0: [
+				(0xff, 0xff, 0xff),
+				(0xff, 0x80, 0x80),
+				(0xc0, 0x00, 0x00),
+				(0, 0, 0),
+			],
+			obj1: [
+				(0xff, 0xff, 0xff),
+				(0x80, 0xa0, 0xff),
+				(0x00, 0x20, 0xc0),
+				(0, 0, 0),
+			],
+		},
+	),
+];
+
+pub fn color_dmg(n: u8, palette: u8, shades: &Shades) -> (u8, u8, u8) {
 	let pcolor = (palette >> (n * 2)) & 0b11;
-	let c = 0x55 * (3 - pcolor);
-	(c, c, c)
+	shades[pcolor as usize]
+}
+
+pub fn rgb555(c: u16) -> (u8, u8, u8) {
+	let c5_to_c8 = |c: u16| (((c & 0x1f) << 3) | ((c & 0x1f) >> 2)) as u8;
+	(c5_to_c8(c), c5_to_c8(c >> 5), c5_to_c8(c >> 10))
 }
 
-pub fn render_dot(gb: &mut GB, lx: u64, sprites: &Vec<Sprite>) {
+pub fn color_cgb(n: u8, palette: &[u8; 8]) -> (u8, u8, u8) {
+	rgb555(u16::from_le_bytes([
+		palette[n as usize * 2],
+		palette[n as usize * 2 + 1],
+	]))
+}
+
+fn color_bg(n: u8, io: &IoReg) -> (u8, u8, u8) {
+	if io.dmg_compat {
+		// the DMG palette picks a color out of CGB palette 0
+		color_cgb((io.bgp >> (n * 2)) & 0b11, &io.bg_palette_ram[0])
+	} else {
+		color_dmg(n, io.bgp, &io.dmg_palettes.bg)
+	}
+}
+
+fn color_obj(n: u8, io: &IoReg, dmg_palette: bool) -> (u8, u8, u8) {
+	let (palette, shades) = match dmg_palette {
+		false => (io.obp0, &io.dmg_palettes.obj0),
+		true => (io.obp1, &io.dmg_palettes.obj1),
+	};
+	if io.dmg_compat {
+		color_cgb(
+			(palette >> (n * 2)) & 0b11,
+			&io.obj_palette_ram[dmg_palette as usize],
+		)
+	} else {
+		color_dmg(n, palette, shades)
+	}
+}
+
+// Color index of a pixel in a BG/window tile map
+fn map_pixel(bus: &Bus, tile_map_area: usize, x: usize, y: usize) -> u8 {
+	let mut itile = bus.vram[tile_map_area + ((x >> 3) + (y >> 3) * 32)] as usize;
+
+	if bus.io.lcdc & 0b10000 == 0 && itile & 0x80 == 0 {
+		itile |= 0x100;
+	}
+
+	let tile_data = &bus.vram[itile * 16..itile * 16 + 16];
+
+	let tile_x = x & 0b111;
+	let tile_y = y & 0b111;
+	let b1 = (tile_data[tile_y * 2 + 0] >> (7 - tile_x)) & 1;
+	let b2 = (tile_data[tile_y * 2 + 1] >> (7 - tile_x)) & 1;
+	b1 | (b2 << 1)
+}
+
+fn render_dot(bus: &mut Bus, lx: u64) {
 	if lx < 80 {
 		return;
 	}
 	let lcd_x = lx as usize - 80;
-	let lcd_y = gb.bus.io.ly as usize;
+	let lcd_y = bus.io.ly as usize;
 	if lcd_x >= 160 {
 		return;
 	}
-	if gb.bus.io.ly > 143 {
+	if bus.io.ly > 143 {
 		return;
 	}
 
-	let window_enable = gb.bus.io.lcdc & 0b_0010_0000 != 0;
+	let window_enable = bus.io.lcdc & 0b_0010_0000 != 0;
 
-	let wx = gb.bus.io.wx as usize;
-	let wy = gb.bus.io.wy as usize;
+	let wx = bus.io.wx as usize;
+	let wy = bus.io.wy as usize;
 
 	let map_pallete_index = if window_enable && wy <= lcd_y && wx <= lcd_x + 7 {
 		let win_y = lcd_y - wy;
 		let win_x = lcd_x + 7 - wx;
 
-		let tile_map_area = match gb.bus.io.lcdc & 0b_0100_0000 {
+		let tile_map_area = match bus.io.lcdc & 0b_0100_0000 {
 			0 => 0x1800,
 			_ => 0x1C00,
 		};
-
-		let mut itile = gb.bus.vram[tile_map_area + ((win_x >> 3) + (win_y >> 3) * 32)] as usize;
-
-		if gb.bus.io.lcdc & 0b10000 == 0 && itile & 0x80 == 0 {
-			itile |= 0x100;
-		}
-
-		let tile_data = &gb.bus.vram[itile * 16..itile * 16 + 16];
-
-		let tile_x = win_x & 0b111;
-		let tile_y = win_y & 0b111;
-		let b1 = (tile_data[tile_y * 2 + 0] >> (7 - tile_x)) & 1;
-		let b2 = (tile_data[tile_y * 2 + 1] >> (7 - tile_x)) & 1;
-		let pallete_index = b1 | (b2 << 1);
-
-		let (r, g, b) = color_dmg(pallete_index, gb.bus.io.bgp);
-
-		gb.framebuffer[0 + 3 * (lcd_x + 160 * lcd_y)] = r;
-		gb.framebuffer[1 + 3 * (lcd_x + 160 * lcd_y)] = g;
-		gb.framebuffer[2 + 3 * (lcd_x + 160 * lcd_y)] = b;
-
-		pallete_index
+		map_pixel(bus, tile_map_area, win_x, win_y)
 	} else {
-		let bg_x = 0xff & ((gb.bus.io.scx as usize) + lcd_x);
-		let bg_y = 0xff & ((gb.bus.io.scy as usize) + lcd_y);
+		let bg_x = 0xff & ((bus.io.scx as usize) + lcd_x);
+		let bg_y = 0xff & ((bus.io.scy as usize) + lcd_y);
 
-		let tile_map_area = match gb.bus.io.lcdc & 0b1000 {
+		let tile_map_area = match bus.io.lcdc & 0b1000 {
 			0 => 0x1800,
 			_ => 0x1C00,
 		};
-		let mut itile = gb.bus.vram[tile_map_area + ((bg_x >> 3) + (bg_y >> 3) * 32)] as usize;
-
-		if gb.bus.io.lcdc & 0b10000 == 0 && itile & 0x80 == 0 {
-			itile |= 0x100;
-		}
-
-		let tile_data = &gb.bus.vram[itile * 16..itile * 16 + 16];
-
-		let tile_x = bg_x & 0b111;
-		let tile_y = bg_y & 0b111;
-		let b1 = (tile_data[tile_y * 2 + 0] >> (7 - tile_x)) & 1;
-		let b2 = (tile_data[tile_y * 2 + 1] >> (7 - tile_x)) & 1;
-		let pallete_index = b1 | (b2 << 1);
+		map_pixel(bus, tile_map_area, bg_x, bg_y)
+	};
 
-		// TODO: BG transparency
+	// TODO: BG transparency
 
-		let (r, g, b) = color_dmg(pallete_index, gb.bus.io.bgp);
-		gb.framebuffer[0 + 3 * (lcd_x + 160 * lcd_y)] = r;
-		gb.framebuffer[1 + 3 * (lcd_x + 160 * lcd_y)] = g;
-		gb.framebuffer[2 + 3 * (lcd_x + 160 * lcd_y)] = b;
+	let (r, g, b) = color_bg(map_pallete_index, &bus.io);
+	bus.io.sgb.screen[lcd_x + 160 * lcd_y] = (bus.io.bgp >> (map_pallete_index * 2)) & 0b11;
+	bus.framebuffer[0 + 3 * (lcd_x + 160 * lcd_y)] = r;
+	bus.framebuffer[1 + 3 * (lcd_x + 160 * lcd_y)] = g;
+	bus.framebuffer[2 + 3 * (lcd_x + 160 * lcd_y)] = b;
 
-		pallete_index
-	};
-	let sprite_h = match gb.bus.io.lcdc & 0b100 {
+	let sprite_h = match bus.io.lcdc & 0b100 {
 		0 => 8,
 		_ => 16,
 	};
-	for sprite in sprites {
+	for sprite in &bus.sprites {
 		if sprite.x <= lcd_x + 8 && sprite.x > lcd_x {
 			let mut s_x = lcd_x + 8 - sprite.x;
 			let mut s_y = lcd_y + 16 - sprite.y;
@@ -149,7 +283,7 @@ pub fn render_dot(gb: &mut GB, lx: u64, sprites: &Vec<Sprite>) {
 				s_x = 8 - 1 - s_x
 			}
 
-			let tile_data = &gb.bus.vram[sprite.itile * 16..sprite.itile * 16 + 32];
+			let tile_data = &bus.vram[sprite.itile * 16..sprite.itile * 16 + 32];
 
 			let b1 = (tile_data[s_y * 2 + 0] >> (7 - s_x)) & 1;
 			let b2 = (tile_data[s_y * 2 + 1] >> (7 - s_x)) & 1;
@@ -165,16 +299,16 @@ pub fn render_dot(gb: &mut GB, lx: u64, sprites: &Vec<Sprite>) {
 				continue;
 			}
 
+			let (r, g, b) = color_obj(pallete_index, &bus.io, sprite.dmg_palette);
 			let palette = match sprite.dmg_palette {
-				false => gb.bus.io.obp0,
-				true => gb.bus.io.obp1,
+				false => bus.io.obp0,
+				true => bus.io.obp1,
 			};
+			bus.io.sgb.screen[lcd_x + 160 * lcd_y] = (palette >> (pallete_index * 2)) & 0b11;
 
-			let (r, g, b) = color_dmg(pallete_index, palette);
-
-			gb.framebuffer[0 + 3 * (lcd_x + 160 * lcd_y)] = r;
-			gb.framebuffer[1 + 3 * (lcd_x + 160 * lcd_y)] = g;
-			gb.framebuffer[2 + 3 * (lcd_x + 160 * lcd_y)] = b;
+			bus.framebuffer[0 + 3 * (lcd_x + 160 * lcd_y)] = r;
+			bus.framebuffer[1 + 3 * (lcd_x + 160 * lcd_y)] = g;
+			bus.framebuffer[2 + 3 * (lcd_x + 160 * lcd_y)] = b;
 
 			break; // First sprite in the list wins
 		}